@@ -128,13 +128,86 @@ pub struct Instruction {
     pub argument: u32
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct CpuState {
     pub m: bool,
     pub x: bool,
+    /// Emulation mode, if known. M and X are always set while it is.
+    pub e: Option<bool>,
+    /// Carry flag, only tracked as far as needed to resolve XCE
+    pub carry: Option<bool>,
+    /// Data bank register, if known
+    pub db: Option<u8>,
+    /// Direct page register, if known
+    pub d: Option<u16>,
+    /// Low and high byte of the accumulator, when loaded from an immediate
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+impl CpuState {
+    /// Native mode with unknown emulation and carry flags
+    pub fn new(m: bool, x: bool) -> Self {
+        Self { m, x, e: None, carry: None, db: None, d: None, a: None, b: None }
+    }
+    /// The state after a reset, which always starts in emulation mode
+    /// with DB and D cleared
+    pub fn reset() -> Self {
+        Self { e: Some(true), db: Some(0), d: Some(0), ..Self::new(true, true) }
+    }
+    /// The state in a handler for one of the native mode vectors
+    pub fn native(m: bool, x: bool) -> Self {
+        Self { e: Some(false), ..Self::new(m, x) }
+    }
+    pub fn emulation(&self) -> bool {
+        self.e == Some(true)
+    }
+    /// Merges the state of another path into this one. Registers known on
+    /// both with the same value stay known, anything else becomes unknown.
+    /// M and X can't be unknown since the code has to be decoded somehow, so
+    /// where the paths disagree they're taken as set, decoding as 8 bit; see
+    /// `Flag` for tracking those.
+    pub fn join(&self, other: &CpuState) -> CpuState {
+        fn join<T: PartialEq + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+            if a == b { a } else { None }
+        }
+        CpuState {
+            m: self.m || other.m,
+            x: self.x || other.x,
+            e: join(self.e, other.e),
+            carry: join(self.carry, other.carry),
+            db: join(self.db, other.db),
+            d: join(self.d, other.d),
+            a: join(self.a, other.a),
+            b: join(self.b, other.b),
+        }
+    }
+}
+
+/// M or X over all the paths reaching an instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flag {
+    Clear,
+    Set,
+    /// Clear on some paths and set on others
+    Conflict,
+}
+
+impl From<bool> for Flag {
+    fn from(value: bool) -> Self {
+        if value { Flag::Set } else { Flag::Clear }
+    }
 }
 
-pub fn parse_instr(input: &[u8], CpuState { m, x }: CpuState) -> Option<(usize, Instruction)> {
+impl Flag {
+    pub fn join(self, other: Flag) -> Flag {
+        if self == other { self } else { Flag::Conflict }
+    }
+}
+
+pub fn parse_instr(input: &[u8], state: CpuState) -> Option<(usize, Instruction)> {
+    let m = state.m || state.emulation();
+    let x = state.x || state.emulation();
     let opcode = *input.get(0)?;
     let mnemonic = INSTR[opcode as usize];
     let mode = MODES[opcode as usize];
@@ -173,18 +246,30 @@ impl Instruction {
     pub fn branch(&self) -> bool {
         matches!(self.mnemonic, BCC|BCS|BEQ|BNE|BMI|BPL|BVC|BVS)
     }
-    pub fn apply_flags(&self, CpuState { m, x }: &mut CpuState) {
+    pub fn apply_flags(&self, CpuState { m, x, e, carry, .. }: &mut CpuState) {
         match self.mnemonic {
             REP => {
-                *m &= self.argument & 0x20 == 0;
-                *x &= self.argument & 0x10 == 0;
+                if *e != Some(true) {
+                    *m &= self.argument & 0x20 == 0;
+                    *x &= self.argument & 0x10 == 0;
+                }
+                if self.argument & 0x01 != 0 { *carry = Some(false); }
             }
             SEP => {
                 *m |= self.argument & 0x20 != 0;
                 *x |= self.argument & 0x10 != 0;
+                if self.argument & 0x01 != 0 { *carry = Some(true); }
             },
+            CLC => *carry = Some(false),
+            SEC => *carry = Some(true),
+            XCE => std::mem::swap(e, carry),
+            ADC | SBC | CMP | CPX | CPY | ASL | LSR | ROL | ROR | PLP | RTI => *carry = None,
             _ => {}
         }
+        if *e == Some(true) {
+            *m = true;
+            *x = true;
+        }
     }
     pub fn apply_flags_opt(&self, m: &mut Option<bool>, x: &mut Option<bool>) {
         match self.mnemonic {
@@ -199,32 +284,46 @@ impl Instruction {
             _ => {}
         }
     }
-    pub fn label_target(&self, addr: u32, dbr: u32) -> Option<u32> {
+    /// Whether the operand is an address in the data bank.
+    pub fn uses_dbr(&self) -> bool {
+        !matches!(self.mnemonic, JMP|JSR|PEA) && matches!(self.mode, Abs|Abx|Aby)
+    }
+    /// Whether the operand is an offset from the direct page register.
+    pub fn uses_d(&self) -> bool {
+        matches!(self.mode, Dp|Dpx|Dpy|Idp|Idx|Idy|Idl|Ily)
+    }
+    /// The address the operand refers to, or `None` if it depends on a
+    /// register that isn't known in `state`.
+    pub fn label_target(&self, addr: u32, state: &CpuState) -> Option<u32> {
         match self.mnemonic {
-            JMP|JSR => return Some(self.argument | (addr & 0xFF0000)),
+            JMP|JSR|PEA => return Some(self.argument | (addr & 0xFF0000)),
             MVN|MVP => return None,
             _ => {}
         }
         Some(match self.mode {
-            Sr|Bm => self.argument,
+            Sr|Isy|Bm => self.argument,
             Imp => return None,
-            Dp|Dpx|Dpy|Idp|Idx|Idy|Idl|Ily|Isy => self.argument,
-            Abs|Abx|Aby|Ind|Iax|Ial|Imm => self.argument | (dbr << 16),
+            Dp|Dpx|Dpy|Idp|Idx|Idy|Idl|Ily => (state.d? as u32 + self.argument) & 0xFFFF,
+            Abs|Abx|Aby => self.argument | (state.db? as u32) << 16,
+            Ind|Iax|Ial|Imm => self.argument | (addr & 0xFF0000),
             Abl|Alx => self.argument,
-            Rel => addr + 2 + self.argument as i8 as u32,
-            Rll => addr + 3 + self.argument as i8 as u32,
+            Rel|Rll => self.relative_target(addr),
         })
     }
+    /// Where a relative operand points. Like the program counter, it wraps
+    /// around within the bank instead of carrying into the next one.
+    fn relative_target(&self, addr: u32) -> u32 {
+        let target = match self.mode {
+            Rll => (addr + 3).wrapping_add(self.argument as i16 as u32),
+            _ => (addr + 2).wrapping_add(self.argument as i8 as u32),
+        };
+        addr & 0xFF0000 | target & 0xFFFF
+    }
     pub fn jump_addr(&self, addr: u32) -> Option<u32> {
         Some(match self.mnemonic {
             JMP if self.mode == Abs => (addr & 0xFF0000) | self.argument,
             JML if self.mode == Abl => self.argument,
-            BRA|BCC|BCS|BEQ|BMI|BNE|BPL|BVC|BVS => {
-                (addr + 2).wrapping_add(self.argument as i8 as u32)
-            },
-            BRL => {
-                (addr + 3).wrapping_add(self.argument as i16 as u32)
-            },
+            BRA|BCC|BCS|BEQ|BMI|BNE|BPL|BVC|BVS|BRL => self.relative_target(addr),
             _ => return None
         })
     }
@@ -239,12 +338,7 @@ impl Instruction {
         Some(match self.mnemonic {
             JMP|JSR if self.mode == Abs => (addr & 0xFF0000) | self.argument,
             JML|JSL if self.mode == Abl => self.argument,
-            BRA|BCC|BCS|BEQ|BMI|BNE|BPL|BVC|BVS => {
-                (addr + 2).wrapping_add(self.argument as i8 as u32)
-            },
-            BRL => {
-                (addr + 3).wrapping_add(self.argument as i16 as u32)
-            },
+            BRA|BCC|BCS|BEQ|BMI|BNE|BPL|BVC|BVS|BRL => self.relative_target(addr),
             _ => return None
         })
     }
@@ -1,23 +1,113 @@
-use crate::rom::Rom;
-use crate::cpu::{self, Instruction, Mnemonic, CpuState};
+use crate::rom::{Rom, Mapper, MmcRegister, Area};
+use crate::cpu::{self, Instruction, Mnemonic, CpuState, Flag};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
 use serde_derive::{Serialize, Deserialize};
 
+/// How many times code gets decoded again before giving up on M and X settling
+const MAX_PASSES: usize = 8;
+
 pub struct Disassembler {
     pub rom: Rom,
     pub entries: HashMap<u32, Entry>,
     pub labels: HashSet<u32>,
     pub returns: HashSet<u32>,
     pub subroutines: HashMap<u32, Subroutine>,
-    pub extra_rules: Vec<Rule>,
     pub label_names: HashMap<u32, String>,
+    /// References to each address, keyed by its canonical form
+    pub xrefs: HashMap<u32, Vec<Xref>>,
+    /// Basic blocks by start address
+    pub blocks: BTreeMap<u32, BasicBlock>,
+    /// Problems found during analysis, in the order they came up
+    pub diagnostics: Vec<Diagnostic>,
+    /// What's in `diagnostics` already, so it doesn't get reported twice
+    reported: HashSet<(u32, Category, String)>,
+    /// M, X and E to decode with where dataflow found an earlier pass used
+    /// something else
+    hints: HashMap<u32, (bool, bool, Option<bool>)>,
+    /// Subroutines known not to return, from `Rule::NoReturn`
+    pub no_return: HashSet<u32>,
+    /// Jump table dispatchers and whether their pointers are long
+    pub dispatchers: HashMap<u32, bool>,
+    /// CPU state to assume whenever analysis reaches an address
+    pub forced_states: HashMap<u32, ForcedState>,
+    /// CPU state to assume on entry to a subroutine
+    pub entry_states: HashMap<u32, ForcedState>,
+    /// Ranges that are never decoded as code, by start address
+    pub data_regions: BTreeMap<u32, DataRegion>,
+    /// Character tables for `DataKind::Text`, by name
+    pub text_tables: HashMap<String, BTreeMap<u8, String>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// An instruction would start in the middle of one decoded earlier
+    OverlappingInstruction,
+    /// Pulling more off the stack than was pushed
+    StackUnderflow,
+    /// M or X depends on the path taken, or can't be restored
+    UnknownFlagState,
+    /// Code, vectors or tables in places that aren't mapped
+    UnmappedTarget,
+    /// BRK, COP, STP or WDM, which are more often data than code
+    SuspiciousOpcode,
+    /// A path runs into a range marked as data
+    DataRegion,
+    /// A dispatcher call whose jump table needs a rule
+    JumpTable,
+    /// A rule that doesn't make sense for this ROM
+    BadRule,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub pc: u32,
+    /// Subroutine the problem was found in, if it's in code at all
+    pub subroutine: Option<u32>,
+    pub category: Category,
+    pub message: String,
 }
 
-#[derive(Clone,Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum XrefKind {
+    Call,
+    Jump,
+    Branch,
+    Read,
+    Write,
+    ReadModifyWrite,
+    /// The address is used as a pointer: indirect operands, PEA/PEI/PER
+    Pointer,
+    /// An entry of a jump table, from the call that dispatches it
+    JumpTable,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Xref {
+    pub kind: XrefKind,
+    /// Address of the referencing instruction
+    pub from: u32,
+    pub instr: Instruction,
+}
+
+#[derive(Clone,Debug,PartialEq)]
 pub enum StackDataType {
     CpuState { state: CpuState, sr_state: SrCpuState },
+    /// A known byte, e.g. from PHK or PEA
     Bank(u8),
+    /// DB as pushed by PHB, along with how the subroutine had changed it
+    SavedDb { db: Option<u8>, affect_db: Option<Option<u8>> },
+    /// Either byte of D as pushed by PHD, like `SavedDb`
+    SavedD { d: Option<u16>, affect_d: Option<Option<u16>> },
     Data,
     RetAddr,
 }
@@ -25,24 +115,172 @@ pub enum StackDataType {
 #[derive(Clone)]
 pub struct Entry {
     pub stack: Vec<StackDataType>,
+    /// Merged over every path reaching the instruction
     pub state: CpuState,
     pub instr: Instruction,
     pub subroutine: u32,
+    /// M and X over every path, where `state` has the ones used for decoding
+    pub m: Flag,
+    pub x: Flag,
 }
 pub struct DataEntry {
     
 }
 
-#[derive(Copy,Clone,Default,Debug)]
+#[derive(Copy,Clone,Default,Debug,PartialEq)]
 pub struct SrCpuState {
     pub affect_m: Option<bool>,
     pub affect_x: Option<bool>,
+    /// The data bank the subroutine leaves behind, which may be unknown
+    pub affect_db: Option<Option<u8>>,
+    /// Likewise for the direct page register
+    pub affect_d: Option<Option<u16>>,
 }
 
-#[derive(Copy,Clone,Default,Debug)]
+impl SrCpuState {
+    /// Merges the effect of another way out of a subroutine into this one.
+    pub fn join(&self, other: &SrCpuState) -> SrCpuState {
+        fn flag(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+            match (a, b) {
+                (a, b) if a == b => a,
+                // a path leaving the flag alone most likely already runs in
+                // the mode the others switch to
+                (None, c) | (c, None) => c,
+                // like anywhere else paths disagree, decode as 8 bit
+                _ => Some(true),
+            }
+        }
+        fn reg<T: PartialEq + Copy>(a: Option<Option<T>>, b: Option<Option<T>>) -> Option<Option<T>> {
+            if a == b { a } else { Some(None) }
+        }
+        SrCpuState {
+            affect_m: flag(self.affect_m, other.affect_m),
+            affect_x: flag(self.affect_x, other.affect_x),
+            affect_db: reg(self.affect_db, other.affect_db),
+            affect_d: reg(self.affect_d, other.affect_d),
+        }
+    }
+    /// The effect of this followed by `then`.
+    pub fn then(&self, then: &SrCpuState) -> SrCpuState {
+        SrCpuState {
+            affect_m: then.affect_m.or(self.affect_m),
+            affect_x: then.affect_x.or(self.affect_x),
+            affect_db: then.affect_db.or(self.affect_db),
+            affect_d: then.affect_d.or(self.affect_d),
+        }
+    }
+}
+
+/// What dataflow knows on the way into an instruction.
+#[derive(Clone, PartialEq)]
+struct Flow {
+    state: CpuState,
+    m: Flag,
+    x: Flag,
+    sr_state: SrCpuState,
+    stack: Vec<StackDataType>,
+}
+
+impl Flow {
+    fn new(state: CpuState, stack: Vec<StackDataType>) -> Self {
+        Flow { state, m: state.m.into(), x: state.x.into(), sr_state: Default::default(), stack }
+    }
+    fn join(&self, other: &Flow) -> Flow {
+        Flow {
+            state: self.state.join(&other.state),
+            m: self.m.join(other.m),
+            x: self.x.join(other.x),
+            sr_state: self.sr_state.join(&other.sr_state),
+            // paths that disagree on the depth only share what's at the bottom
+            stack: self.stack.iter().zip(other.stack.iter())
+                .map(|(a, b)| if a == b { a.clone() } else { StackDataType::Data })
+                .collect(),
+        }
+    }
+    fn force(&mut self, state: &ForcedState) {
+        state.apply(&mut self.state);
+        if let Some(c) = state.m { self.m = c.into(); }
+        if let Some(c) = state.x { self.x = c.into(); }
+        // emulation mode pins both, so no path can disagree on them
+        if self.state.emulation() {
+            self.m = Flag::Set;
+            self.x = Flag::Set;
+        }
+    }
+    /// Picks up after a call to a subroutine with the given effect.
+    fn returned(&mut self, effect: &SrCpuState) {
+        if let Some(c) = effect.affect_m { self.sr_state.affect_m = Some(c); self.state.m = c; self.m = c.into(); }
+        if let Some(c) = effect.affect_x { self.sr_state.affect_x = Some(c); self.state.x = c; self.x = c.into(); }
+        if let Some(c) = effect.affect_db { self.sr_state.affect_db = Some(c); self.state.db = c; }
+        if let Some(c) = effect.affect_d { self.sr_state.affect_d = Some(c); self.state.d = c; }
+        self.state.a = None;
+        self.state.b = None;
+    }
+}
+
+/// Joins `flow` into what's known on the way into the block at `to`,
+/// returning whether that changed. Places where M or X start to differ
+/// between paths go in `conflicts`, unless `forced` settles them there.
+fn merge_flow(ins: &mut HashMap<u32, Flow>, conflicts: &mut BTreeSet<(u32, char)>, to: u32, mut flow: Flow, forced: Option<&ForcedState>) -> bool {
+    if let Some(c) = forced {
+        flow.force(c);
+    }
+    let joined = match ins.get(&to) {
+        Some(old) => {
+            for (name, a, b) in [('M', old.m, flow.m), ('X', old.x, flow.x)] {
+                if a != b && a != Flag::Conflict && b != Flag::Conflict {
+                    conflicts.insert((to, name));
+                }
+            }
+            old.join(&flow)
+        }
+        None => flow,
+    };
+    if ins.get(&to) == Some(&joined) {
+        return false;
+    }
+    ins.insert(to, joined);
+    true
+}
+
+#[derive(Clone,Default,Debug)]
 pub struct Subroutine {
     pub sr_effect: SrCpuState,
     pub divergent: bool,
+    /// Start addresses of the basic blocks making up the subroutine
+    pub blocks: Vec<u32>,
+}
+
+/// A run of instructions that is only ever entered at the top and left at
+/// the bottom.
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub start: u32,
+    /// Address of the last instruction
+    pub last: u32,
+    pub subroutine: u32,
+    pub successors: Vec<Edge>,
+    pub predecessors: Vec<Edge>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Edge {
+    /// Start of the block the edge leaves
+    pub from: u32,
+    /// Start of the block the edge enters
+    pub to: u32,
+    pub kind: EdgeKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    Fallthrough,
+    BranchTaken,
+    Jump,
+    /// From a block ending in a call to the code after it
+    CallReturn,
+    /// From a dispatching call to one of the jump table entries
+    JumpTable,
 }
 
 pub struct QueueEntry {
@@ -54,11 +292,113 @@ pub struct QueueEntry {
 #[derive(Deserialize, Serialize, Clone)]
 pub enum Rule {
     JumpTable { pc: u32, size: u32, long: bool },
+    /// A subroutine that never returns to its caller
+    NoReturn { pc: u32 },
+    /// A subroutine like SMW's ExecutePtr, which jumps through the table of
+    /// pointers following the call to it instead of returning. Calls to it
+    /// without a `JumpTable` rule giving the table's size show up as problems.
+    Dispatcher { pc: u32, long: bool },
+    /// Value written to one of the SA-1 CXB/DXB/EXB/FXB registers
+    Sa1Bank { register: MmcRegister, value: u8 },
+    /// Values written to the SA-1 CRV/CNV/CIV vector registers. Immediate
+    /// stores to them are picked up by analysis, anything else needs this
+    /// rule or the SA-1 side isn't analyzed at all.
+    Sa1Vectors { reset: Option<u16>, nmi: Option<u16>, irq: Option<u16> },
+    /// Overrides the CPU state whenever analysis reaches `pc`
+    State { pc: u32, state: ForcedState },
+    /// Overrides the CPU state a subroutine is called with
+    EntryState { pc: u32, state: ForcedState },
+    /// Code that's only reached in ways analysis can't follow, like pointers
+    /// computed at runtime. Anything not in `state` is unknown, with M and X set.
+    Entry { pc: u32, state: ForcedState },
+    /// Bytes from `start` up to `end` that are never decoded as code, and
+    /// how to show them
+    Data {
+        start: u32,
+        end: u32,
+        #[serde(default)]
+        kind: DataKind,
+        /// Items on each line, with a default depending on the kind
+        #[serde(default)]
+        per_line: Option<usize>,
+    },
+    /// Characters for text in `DataKind::Text`, as in a .tbl file
+    TextTable { name: String, chars: BTreeMap<u8, String> },
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DataKind {
+    #[default]
+    Bytes,
+    Words,
+    Longs,
+    Dwords,
+    /// Pointers into the bank the table is in
+    WordPtrs,
+    LongPtrs,
+    Ascii,
+    /// Text using the characters from a `Rule::TextTable`
+    Text { table: String },
+}
+
+impl DataKind {
+    pub fn item_size(&self) -> usize {
+        match self {
+            DataKind::Bytes | DataKind::Ascii | DataKind::Text { .. } => 1,
+            DataKind::Words | DataKind::WordPtrs => 2,
+            DataKind::Longs | DataKind::LongPtrs => 3,
+            DataKind::Dwords => 4,
+        }
+    }
+    pub fn default_per_line(&self) -> usize {
+        match self {
+            DataKind::Bytes => 16,
+            DataKind::Words | DataKind::Longs => 8,
+            DataKind::Dwords => 4,
+            DataKind::WordPtrs | DataKind::LongPtrs => 1,
+            DataKind::Ascii | DataKind::Text { .. } => 32,
+        }
+    }
+}
+
+/// A range of data from a `Rule::Data`.
+#[derive(Clone, Debug)]
+pub struct DataRegion {
+    pub end: u32,
+    /// Where it is in the ROM, so code running into a mirror is caught too
+    pub offsets: Range<usize>,
+    pub kind: DataKind,
+    pub per_line: usize,
+}
+
+/// Parts of the CPU state set by a rule, anything left out is kept.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForcedState {
+    pub m: Option<bool>,
+    pub x: Option<bool>,
+    pub e: Option<bool>,
+    pub db: Option<u8>,
+    pub d: Option<u16>,
+}
+
+impl ForcedState {
+    pub fn apply(&self, state: &mut CpuState) {
+        if let Some(c) = self.e { state.e = Some(c); }
+        if let Some(c) = self.m { state.m = c; }
+        if let Some(c) = self.x { state.x = c; }
+        if state.emulation() {
+            state.m = true;
+            state.x = true;
+        }
+        if let Some(c) = self.db { state.db = Some(c); }
+        if let Some(c) = self.d { state.d = Some(c); }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Line {
     pub pc: u32,
+    /// Number of bytes the line covers, 0 for labels and spacing
     pub len: usize,
     pub text: String,
     pub kind: LineKind
@@ -72,6 +412,21 @@ pub enum LineKind {
     Spacing,
 }
 
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Category::OverlappingInstruction => write!(f, "overlapping instruction"),
+            Category::StackUnderflow => write!(f, "stack underflow"),
+            Category::UnknownFlagState => write!(f, "unknown flag state"),
+            Category::UnmappedTarget => write!(f, "unmapped target"),
+            Category::SuspiciousOpcode => write!(f, "suspicious opcode"),
+            Category::DataRegion => write!(f, "code in data"),
+            Category::JumpTable => write!(f, "jump table"),
+            Category::BadRule => write!(f, "bad rule"),
+        }
+    }
+}
+
 impl Disassembler {
     pub fn new(rom: Rom) -> Self {
         Self {
@@ -80,63 +435,533 @@ impl Disassembler {
             labels: HashSet::new(),
             returns: HashSet::new(),
             subroutines: HashMap::new(),
-            extra_rules: vec![],
             label_names: HashMap::new(),
+            xrefs: HashMap::new(),
+            blocks: BTreeMap::new(),
+            diagnostics: vec![],
+            reported: HashSet::new(),
+            hints: HashMap::new(),
+            no_return: HashSet::new(),
+            dispatchers: HashMap::new(),
+            forced_states: HashMap::new(),
+            entry_states: HashMap::new(),
+            data_regions: BTreeMap::new(),
+            text_tables: HashMap::new(),
         }
     }
     pub fn process_rules<'a>(&mut self, rules: impl IntoIterator<Item=&'a Rule>) {
-        //self.process(QueueEntry { pc: 0xCCE0, stack: vec![], sr_state: Default::default(), state: CpuState { m: true, x: true } });
-        let start = self.rom.load_u16(0xFFFC) as u32;
-        let irq = self.rom.load_u16(0xFFEE) as u32;
-        let nmi = self.rom.load_u16(0xFFEA) as u32;
-        self.process(QueueEntry { pc: start, stack: vec![], sr_state: Default::default(), state: CpuState { m: true, x: true } });
-        self.process(QueueEntry { pc: irq, stack: vec![], sr_state: Default::default(), state: CpuState { m: true, x: true } });
-        self.process(QueueEntry { pc: nmi, stack: vec![], sr_state: Default::default(), state: CpuState { m: true, x: true } });
+        let rules = rules.into_iter().collect::<Vec<_>>();
+        // mapping has to be settled before anything is read from the rom
+        for i in rules.iter() { match i {
+            Rule::Sa1Bank { register, value } => self.rom.sa1.set_mmc(*register, *value),
+            Rule::Sa1Vectors { reset, nmi, irq } => {
+                if reset.is_some() { self.rom.sa1.crv = *reset; }
+                if nmi.is_some() { self.rom.sa1.cnv = *nmi; }
+                if irq.is_some() { self.rom.sa1.civ = *irq; }
+            }
+            // and so does anything changing how code is followed
+            Rule::NoReturn { pc } => { self.no_return.insert(*pc); }
+            Rule::Dispatcher { pc, long } => { self.dispatchers.insert(*pc, *long); }
+            Rule::State { pc, state } => { self.forced_states.insert(*pc, *state); }
+            Rule::EntryState { pc, state } => { self.entry_states.insert(*pc, *state); }
+            _ => {}
+        } }
+        for i in rules.iter() { match i {
+            Rule::Data { start, end, kind, per_line } => match self.rom.try_map_rom(*start) {
+                Ok(c) => {
+                    let offsets = c..c + end.saturating_sub(*start) as usize;
+                    let per_line = per_line.unwrap_or(kind.default_per_line()).max(1);
+                    self.data_regions.insert(*start, DataRegion { end: *end, offsets, kind: kind.clone(), per_line });
+                }
+                Err(e) => self.report(Severity::Error, *start, None, Category::BadRule, format!("data region isn't in the ROM: {}", e)),
+            },
+            Rule::TextTable { name, chars } => { self.text_tables.insert(name.clone(), chars.clone()); }
+            _ => {}
+        } }
+        for (&start, region) in self.data_regions.clone().iter() {
+            if let DataKind::Text { table } = &region.kind {
+                if !self.text_tables.contains_key(table) {
+                    self.report(Severity::Warning, start, None, Category::BadRule, format!("there's no text table named \"{}\"", table));
+                }
+            }
+        }
+        // problems with the rules stay, anything else is found again on every pass
+        let kept = self.diagnostics.len();
+        self.hints.clear();
+        for pass in 1.. {
+            self.clear_analysis(kept);
+            let roots = self.discover(&rules);
+            self.build_cfg();
+            let redecode = self.dataflow(roots);
+            if redecode.is_empty() {
+                // the SA-1 side can only be followed once its vectors are known
+                if self.find_sa1_vectors() {
+                    continue;
+                }
+                break;
+            }
+            if pass >= MAX_PASSES {
+                for (&pc, _) in redecode.iter() {
+                    let sub = self.entries.get(&pc).map(|c| c.subroutine);
+                    self.report(Severity::Warning, pc, sub, Category::UnknownFlagState, "M and X here don't settle, this may be decoded wrong".into());
+                }
+                break;
+            }
+            self.hints.extend(redecode);
+        }
+        if self.rom.mapper() == Mapper::Sa1 && self.rom.sa1.crv.is_none() {
+            self.report(Severity::Warning, 0x2203, None, Category::UnmappedTarget,
+                "the SA-1 reset vector isn't set anywhere analysis can follow, add a Sa1Vectors rule for its code".into());
+        }
+        let mut pcs = self.entries.keys().copied().collect::<Vec<_>>();
+        pcs.sort();
+        for pc in pcs {
+            let (instr, state) = (self.entries[&pc].instr, self.entries[&pc].state);
+            self.record_xrefs(pc, &instr, &state);
+        }
+    }
+    /// Forgets everything found by analyzing the code, except for the first
+    /// `kept` diagnostics.
+    fn clear_analysis(&mut self, kept: usize) {
+        self.entries.clear();
+        self.labels.clear();
+        self.returns.clear();
+        self.subroutines.clear();
+        self.xrefs.clear();
+        self.blocks.clear();
+        self.diagnostics.truncate(kept);
+        self.reported = self.diagnostics.iter().map(|c| (c.pc, c.category, c.message.clone())).collect();
+    }
+    /// Fills in SA-1 vectors not set by rules from the values the analyzed
+    /// code stores to $2203-$2208, returning whether any were found.
+    fn find_sa1_vectors(&mut self) -> bool {
+        use Mnemonic::*;
+        if self.rom.mapper() != Mapper::Sa1 {
+            return false;
+        }
+        // bytes stored to each register, or None if that's not always the same
+        let mut writes: HashMap<u32, Option<u8>> = HashMap::new();
+        for (&pc, entry) in self.entries.iter() {
+            let (instr, state) = (entry.instr, entry.state);
+            if !matches!(instr.mnemonic, STA|STZ) || !matches!(instr.mode, cpu::Mode::Abs|cpu::Mode::Abl) {
+                continue;
+            }
+            let Some(target) = instr.label_target(pc, &state) else { continue };
+            if !matches!(target >> 16, 0x00..=0x3F | 0x80..=0xBF) {
+                continue;
+            }
+            let bytes = match instr.mnemonic {
+                STZ => [Some(0), Some(0)],
+                _ => [state.a, state.b],
+            };
+            for (reg, byte) in (target & 0xFFFF..).zip(bytes).take(if state.m { 1 } else { 2 }) {
+                if (0x2203..=0x2208).contains(&reg) {
+                    let c = writes.entry(reg).or_insert(byte);
+                    if *c != byte { *c = None; }
+                }
+            }
+        }
+        let value = |reg: u32| match (writes.get(&reg), writes.get(&(reg + 1))) {
+            (Some(&Some(lo)), Some(&Some(hi))) => Some(u16::from_le_bytes([lo, hi])),
+            _ => None,
+        };
+        let sa1 = &mut self.rom.sa1;
+        let mut found = false;
+        for (reg, vector) in [(0x2203, &mut sa1.crv), (0x2205, &mut sa1.cnv), (0x2207, &mut sa1.civ)] {
+            if vector.is_none() {
+                *vector = value(reg);
+                found |= vector.is_some();
+            }
+        }
+        found
+    }
+    /// Follows the code from every entry point, decoding it along the way,
+    /// and returns the entry points with the state they start in.
+    fn discover(&mut self, rules: &[&Rule]) -> Vec<(u32, Flow)> {
+        let mut roots = vec![];
+        // reset, irq, nmi
+        for (vector, state) in [(0xFFFC, CpuState::reset()), (0xFFEE, CpuState::native(true, true)), (0xFFEA, CpuState::native(true, true))] {
+            match self.rom.try_load_u16(vector) {
+                Ok(pc) => self.enter(&mut roots, QueueEntry { pc: pc as u32, stack: vec![], sr_state: Default::default(), state }),
+                Err(e) => self.report(Severity::Error, vector, None, Category::UnmappedTarget, format!("can't read vector: {}", e)),
+            }
+        }
+        if self.rom.mapper() == Mapper::Sa1 {
+            // the SA-1 cpu fetches its vectors from the registers, not the rom
+            let sa1 = self.rom.sa1;
+            let vectors = [(sa1.crv, CpuState::reset()), (sa1.cnv, CpuState::native(true, true)), (sa1.civ, CpuState::native(true, true))];
+            for (pc, state) in vectors {
+                let Some(pc) = pc else { continue };
+                self.enter(&mut roots, QueueEntry { pc: pc as u32, stack: vec![], sr_state: Default::default(), state });
+            }
+        }
         let mut jt = HashSet::new();
-        for i in rules.into_iter() { match i {
+        for i in rules.iter() { match i {
             Rule::JumpTable { pc, size, long } => {
                 jt.insert(pc);
+                // targets start out the way the dispatching call was made
+                let state = self.entries.get(pc).map(|c| CpuState { a: None, b: None, ..c.state }).unwrap_or(CpuState::new(true, true));
+                let call = self.entries.get(pc).map(|c| c.instr)
+                    .or_else(|| self.rom.try_slice(*pc).ok().and_then(|c| cpu::parse_instr(c, state)).map(|c| c.1));
                 let width = if *long { 3 } else { 2 };
                 for i in 0..*size {
                     let addr = pc + 4 + i*width;
                     let addr = if *long {
-                        self.rom.load_u24(addr)
+                        self.rom.try_load_u24(addr)
                     } else {
-                        self.rom.load_u16(addr) as u32 | (pc & 0xFF0000)
+                        self.rom.try_load_u16(addr).map(|c| c as u32 | (pc & 0xFF0000))
+                    };
+                    let addr = match addr {
+                        Ok(c) => c,
+                        Err(e) => {
+                            let sub = self.entries.get(pc).map(|c| c.subroutine);
+                            self.report(Severity::Warning, *pc, sub, Category::UnmappedTarget, format!("jump table is cut off: {}", e));
+                            break;
+                        }
                     };
                     //println!("Doing jt {:06X} - {:06X}", pc, addr);
-                    self.process(QueueEntry { pc: addr, stack: vec![StackDataType::RetAddr; width as usize], sr_state: Default::default(), state: cpu::CpuState { m: true, x: true }});
-                    //self.xrefs.insert(addr, vec![]);
+                    self.enter(&mut roots, QueueEntry { pc: addr, stack: vec![StackDataType::RetAddr; width as usize], sr_state: Default::default(), state });
+                    if let Some(instr) = call {
+                        self.add_xref(addr, Xref { kind: XrefKind::JumpTable, from: *pc, instr });
+                    }
                 }
             }
+            Rule::Entry { pc, state } => {
+                let mut cpu = CpuState::new(true, true);
+                state.apply(&mut cpu);
+                self.enter(&mut roots, QueueEntry { pc: *pc, stack: vec![], sr_state: Default::default(), state: cpu });
+            }
+            _ => {}
         } }
+        // the table after a dispatcher call is only followed once a rule
+        // says how long it is
+        let mut calls = self.entries.iter()
+            .filter(|(pc, c)| matches!(c.instr.mnemonic, Mnemonic::JSR|Mnemonic::JSL) && !jt.contains(pc))
+            .filter(|(&pc, c)| c.instr.jump_target(pc).map_or(false, |c| self.dispatchers.contains_key(&c)))
+            .map(|(&pc, c)| (pc, c.subroutine))
+            .collect::<Vec<_>>();
+        calls.sort();
+        for (pc, sub) in calls {
+            self.report(Severity::Warning, pc, Some(sub), Category::JumpTable, "the size of the table after this dispatcher call isn't known, add a JumpTable rule for it".into());
+        }
+        roots
+    }
+    /// Analyzes the code at an entry point, noting down how it's entered.
+    fn enter(&mut self, roots: &mut Vec<(u32, Flow)>, entry: QueueEntry) {
+        let mut state = entry.state;
+        if let Some(c) = self.entry_states.get(&entry.pc) {
+            c.apply(&mut state);
+        }
+        roots.push((entry.pc, Flow::new(state, entry.stack.clone())));
+        self.process(entry);
+    }
+    /// Works out the state on the way into every basic block, starting from
+    /// `roots`, and what each subroutine does to M, X, DB and D on its way
+    /// out. Returns the instructions that have to be decoded again, with the
+    /// M, X and E to use.
+    fn dataflow(&mut self, roots: Vec<(u32, Flow)>) -> HashMap<u32, (bool, bool, Option<bool>)> {
+        use Mnemonic::*;
+        let mut ins = HashMap::new();
+        let mut conflicts = BTreeSet::new();
+        let mut redecode = HashMap::new();
+        // what each subroutine does, once a way out of it has been seen
+        let mut effects: HashMap<u32, SrCpuState> = HashMap::new();
+        // blocks that need to know what a subroutine does to carry on
+        let mut waiting: HashMap<u32, HashSet<u32>> = HashMap::new();
+        let mut queue = BTreeSet::new();
+        for (pc, flow) in roots {
+            if self.blocks.contains_key(&pc) && merge_flow(&mut ins, &mut conflicts, pc, flow, self.forced_states.get(&pc)) {
+                queue.insert(pc);
+            }
+        }
+        loop {
+            while let Some(start) = queue.pop_first() {
+                let out = self.transfer(start, ins[&start].clone(), &mut redecode);
+                let (sub, last, successors) = {
+                    let c = &self.blocks[&start];
+                    (c.subroutine, c.last, c.successors.clone())
+                };
+                let instr = self.entries[&last].instr;
+                let owner = |target: u32| self.blocks.get(&target).map(|c| c.subroutine);
+                let mut exits = vec![];
+                let mut outs = vec![];
+                if matches!(instr.mnemonic, RTS|RTL) {
+                    exits.push(out.sr_state);
+                }
+                let callee = instr.jump_target(last).filter(|_| matches!(instr.mnemonic, JSR|JSL));
+                if let Some(target) = callee {
+                    let size = if instr.mnemonic == JSL { 3 } else { 2 };
+                    let mut flow = Flow { sr_state: Default::default(), stack: vec![StackDataType::RetAddr; size], ..out.clone() };
+                    if let Some(c) = self.entry_states.get(&target) {
+                        flow.force(c);
+                    }
+                    outs.push((target, flow));
+                }
+                for edge in successors {
+                    match edge.kind {
+                        EdgeKind::CallReturn => {
+                            let mut flow = out.clone();
+                            match callee.map(|c| (c, owner(c))) {
+                                Some((_, Some(c))) => {
+                                    waiting.entry(c).or_default().insert(start);
+                                    match effects.get(&c) {
+                                        Some(effect) => flow.returned(effect),
+                                        None => continue,
+                                    }
+                                }
+                                // not code that was analyzed, so nothing is known about it
+                                Some((_, None)) => flow.returned(&Default::default()),
+                                None => {
+                                    flow.state.a = None;
+                                    flow.state.b = None;
+                                }
+                            }
+                            outs.push((edge.to, flow));
+                        }
+                        EdgeKind::JumpTable => {
+                            let long = callee.and_then(|c| self.dispatchers.get(&c).copied())
+                                .unwrap_or(instr.mnemonic == JSL);
+                            let width = if long { 3 } else { 2 };
+                            let mut flow = Flow { sr_state: Default::default(), stack: vec![StackDataType::RetAddr; width], ..out.clone() };
+                            flow.state.a = None;
+                            flow.state.b = None;
+                            if let Some(c) = self.entry_states.get(&edge.to) {
+                                flow.force(c);
+                            }
+                            outs.push((edge.to, flow));
+                        }
+                        _ => match owner(edge.to) {
+                            Some(c) if c != sub => {
+                                // running into another subroutine, which
+                                // leaves this one the same way it does
+                                waiting.entry(c).or_default().insert(start);
+                                if let Some(effect) = effects.get(&c) {
+                                    exits.push(out.sr_state.then(effect));
+                                }
+                                outs.push((edge.to, Flow { sr_state: Default::default(), ..out.clone() }));
+                            }
+                            _ => outs.push((edge.to, out.clone())),
+                        },
+                    }
+                }
+                for effect in exits {
+                    let joined = effects.get(&sub).map_or(effect, |c| c.join(&effect));
+                    if effects.get(&sub) != Some(&joined) {
+                        effects.insert(sub, joined);
+                        queue.extend(waiting.get(&sub).into_iter().flatten());
+                    }
+                }
+                for (to, flow) in outs {
+                    if self.blocks.contains_key(&to) && merge_flow(&mut ins, &mut conflicts, to, flow, self.forced_states.get(&to)) {
+                        queue.insert(to);
+                    }
+                }
+            }
+            // subroutines that never got to a return, like ones looping
+            // forever, are taken to leave everything alone
+            let stuck = waiting.keys().filter(|c| !effects.contains_key(c)).copied().collect::<Vec<_>>();
+            if stuck.is_empty() {
+                break;
+            }
+            for sub in stuck {
+                effects.insert(sub, Default::default());
+                queue.extend(waiting[&sub].iter());
+            }
+        }
+        // just a note, since the code is decoded again to match; code where
+        // that doesn't settle gets a warning of its own
+        for (pc, name) in conflicts {
+            let sub = self.entries.get(&pc).map(|c| c.subroutine);
+            self.report(Severity::Info, pc, sub, Category::UnknownFlagState, format!("{} flag differs between paths, decoding as 8 bit", name));
+        }
+        let owners = self.subroutines.keys()
+            .map(|&c| (c, self.blocks.get(&c).map_or(c, |c| c.subroutine)))
+            .collect::<Vec<_>>();
+        for (pc, owner) in owners {
+            self.subroutines.get_mut(&pc).unwrap().sr_effect = effects.get(&owner).copied().unwrap_or_default();
+        }
+        redecode
+    }
+    /// Runs `flow` through the block at `start`, updating the state each
+    /// instruction is shown with, and returns what comes out at the bottom.
+    /// Instructions that would be decoded differently now go in `redecode`.
+    fn transfer(&mut self, start: u32, mut flow: Flow, redecode: &mut HashMap<u32, (bool, bool, Option<bool>)>) -> Flow {
+        use Mnemonic::*;
+        let last = self.blocks[&start].last;
+        let mut pc = start;
+        loop {
+            if let Some(c) = self.forced_states.get(&pc) {
+                flow.force(c);
+            }
+            let instr = self.entries[&pc].instr;
+            let state = flow.state;
+            let decoded = self.rom.try_slice(pc).ok().and_then(|c| cpu::parse_instr(c, state));
+            match decoded {
+                Some((_, c)) if c.size != instr.size => { redecode.insert(pc, (state.m, state.x, state.e)); }
+                _ => { redecode.remove(&pc); }
+            }
+            let entry = self.entries.get_mut(&pc).unwrap();
+            entry.state = state;
+            entry.m = flow.m;
+            entry.x = flow.x;
+            entry.stack = flow.stack.clone();
+
+            let emulation = state.emulation();
+            instr.apply_flags(&mut flow.state);
+            if !emulation {
+                instr.apply_flags_opt(&mut flow.sr_state.affect_m, &mut flow.sr_state.affect_x);
+            }
+            if instr.mnemonic == XCE && flow.state.emulation() {
+                flow.sr_state.affect_m = Some(true);
+                flow.sr_state.affect_x = Some(true);
+            }
+            self.apply_instr(pc, &instr, &mut flow.state, &mut flow.sr_state, &mut flow.stack);
+            // the flags are the same on every path again after being set
+            let bits = match instr.mnemonic {
+                REP | SEP => instr.argument,
+                PLP | XCE => 0x30,
+                _ => 0,
+            };
+            if bits & 0x20 != 0 { flow.m = flow.state.m.into(); }
+            if bits & 0x10 != 0 { flow.x = flow.state.x.into(); }
+            if pc == last {
+                return flow;
+            }
+            pc += instr.size as u32 + 1;
+        }
+    }
+    /// Splits the analyzed code into basic blocks and links them up.
+    pub fn build_cfg(&mut self) {
+        use Mnemonic::*;
+        let mut tables: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (&target, xrefs) in self.xrefs.iter() {
+            for c in xrefs.iter().filter(|c| c.kind == XrefKind::JumpTable) {
+                tables.entry(c.from).or_default().push(target);
+            }
+        }
+        let next = |pc: u32, e: &Entry| pc + e.instr.size as u32 + 1;
+        let ends_block = |e: &Entry| e.instr.branch() || e.instr.divergent() || matches!(e.instr.mnemonic, JSR|JSL);
+        let mut leaders: HashSet<u32> = self.subroutines.keys().copied().collect();
+        for (&pc, e) in self.entries.iter() {
+            if self.labels.contains(&pc) { leaders.insert(pc); }
+            if let Some(target) = e.instr.jump_addr(pc) { leaders.insert(target); }
+            if ends_block(e) { leaders.insert(next(pc, e)); }
+        }
+        leaders.extend(tables.values().flatten());
+
+        let mut pcs = self.entries.keys().copied().collect::<Vec<_>>();
+        pcs.sort();
+        let mut blocks = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for pc in pcs {
+            let entry = &self.entries[&pc];
+            if let Some(block) = current.take() {
+                let last = &self.entries[&block.last];
+                // the previous block runs into this one
+                if next(block.last, last) == pc && !leaders.contains(&pc) && block.subroutine == entry.subroutine {
+                    current = Some(BasicBlock { last: pc, ..block });
+                } else {
+                    blocks.insert(block.start, block);
+                }
+            }
+            let block = current.get_or_insert_with(|| BasicBlock {
+                start: pc, last: pc, subroutine: entry.subroutine, successors: vec![], predecessors: vec![],
+            });
+            if ends_block(entry) {
+                blocks.insert(block.start, current.take().unwrap());
+            }
+        }
+        if let Some(block) = current {
+            blocks.insert(block.start, block);
+        }
+
+        let mut edges = vec![];
+        for block in blocks.values() {
+            let pc = block.last;
+            let entry = &self.entries[&pc];
+            let instr = entry.instr;
+            let mut edge = |to: u32, kind| if blocks.contains_key(&to) {
+                edges.push(Edge { from: block.start, to, kind });
+            };
+            if let Some(targets) = tables.get(&pc) {
+                for &to in targets { edge(to, EdgeKind::JumpTable); }
+            }
+            if instr.branch() {
+                edge(instr.jump_addr(pc).unwrap(), EdgeKind::BranchTaken);
+                edge(next(pc, entry), EdgeKind::Fallthrough);
+            } else if let Some(target) = instr.jump_addr(pc) {
+                edge(target, EdgeKind::Jump);
+            } else if matches!(instr.mnemonic, JSR|JSL) {
+                let divergent = instr.jump_target(pc)
+                    .and_then(|c| self.subroutines.get(&c))
+                    .map_or(false, |c| c.divergent) || tables.contains_key(&pc);
+                if !divergent { edge(next(pc, entry), EdgeKind::CallReturn); }
+            } else if !instr.divergent() {
+                edge(next(pc, entry), EdgeKind::Fallthrough);
+            }
+        }
+        for edge in edges {
+            blocks.get_mut(&edge.from).unwrap().successors.push(edge);
+            blocks.get_mut(&edge.to).unwrap().predecessors.push(edge);
+        }
+
+        for sub in self.subroutines.values_mut() { sub.blocks.clear(); }
+        for block in blocks.values() {
+            if let Some(sub) = self.subroutines.get_mut(&block.subroutine) {
+                sub.blocks.push(block.start);
+            }
+        }
+        self.blocks = blocks;
+    }
+    /// The basic block containing the instruction at `pc`.
+    pub fn block_at(&self, pc: u32) -> Option<&BasicBlock> {
+        self.blocks.range(..=pc).next_back().map(|c| c.1)
+            .filter(|c| pc <= c.last + self.entries[&c.last].instr.size as u32)
+    }
+    /// An instruction as shown in the listing, with operands replaced by
+    /// labels where possible.
+    pub fn instr_text(&self, pc: u32, entry: &Entry) -> String {
+        let mut out = String::new();
+        if let Some(c) = entry.instr.jump_target(pc) {
+            entry.instr.display(Some(&self.get_label(c)), &mut out);
+        } else if let Some(c) = entry.instr.label_target(pc, &entry.state) {
+            if matches!(entry.instr.mode, cpu::Mode::Imm) {
+                entry.instr.display(None, &mut out);
+            } else {
+                entry.instr.display(Some(&self.get_data_label(c)), &mut out);
+            }
+        } else {
+            entry.instr.display(None, &mut out);
+        }
+        out
+    }
+    /// Why an instruction's operand couldn't be resolved to a label, for
+    /// the listing's comment column.
+    pub fn operand_note(&self, entry: &Entry) -> Option<&'static str> {
+        if entry.instr.uses_dbr() && entry.state.db.is_none() {
+            Some("DB unknown")
+        } else if entry.instr.uses_d() && entry.state.d.is_none() {
+            Some("D unknown")
+        } else {
+            None
+        }
     }
     pub fn print_bank(&self, bank: u32) -> Vec<Line> {
         let mut lines = vec![];
-        let mut rpc = 0x8000;
+        let mut rpc = if self.rom.is_rom(bank << 16) { 0 } else { 0x8000 };
         while rpc < 0x10000 {
             let pc = rpc + (bank << 16);
             use std::fmt::Write;
             if self.labels.contains(&pc) || self.label_names.contains_key(&pc) {
                 let mut out = String::new();
-                writeln!(out, "{}:", self.get_label(pc));
+                writeln!(out, "{}:{}", self.get_label(pc), self.xref_hint(pc));
                 lines.push(Line { pc, len: 0, text: out, kind: LineKind::Label });
             }
             if let Some(i) = &self.entries.get(&pc) {
                 let mut out = String::new();
                 write!(out, "    ");
                 let old_len = out.len();
-                if let Some(c) = i.instr.jump_target(pc) {
-                    i.instr.display(Some(&self.get_label(c)), &mut out);
-                } else if let Some(c) = i.instr.label_target(pc, pc >> 16) {
-                    if matches!(i.instr.mode, cpu::Mode::Imm) {
-                        i.instr.display(None, &mut out);
-                    } else {
-                        i.instr.display(Some(&self.get_data_label(c)), &mut out);
-                    }
-                } else {
-                    i.instr.display(None, &mut out);
-                }
+                out.push_str(&self.instr_text(pc, i));
                 /*write!(out, "{}", " ".repeat(48_usize.saturating_sub(out.len()-old_len)));
                 writeln!(out, "; {:06X} | {}{} | {}",
                     pc,
@@ -144,69 +969,113 @@ impl Disassembler {
                     if i.state.x { "X" } else { "x" },
                     i.stack.len()
                 );*/
-                lines.push(Line { pc, len: i.instr.size, text: out, kind: LineKind::Code });
+                lines.push(Line { pc, len: i.instr.size + 1, text: out, kind: LineKind::Code });
                 if i.instr.divergent() {
                     lines.push(Line { pc: pc+i.instr.size as u32 + 1, len: 0, text: "".into(), kind: LineKind::Spacing });
                 }
                 rpc += (i.instr.size + 1) as u32;
+            } else if let Some((text, len)) = self.data_regions.range(..=pc).next_back()
+                .filter(|(_, c)| pc < c.end)
+                .and_then(|(_, c)| self.data_line(pc, c))
+            {
+                lines.push(Line { pc, len, text, kind: LineKind::Data });
+                rpc += len as u32;
             } else {
-                lines.push(Line { pc, len: 1, text: format!("    db ${:02X}", self.rom.load(pc)), kind: LineKind::Data });
+                match self.rom.try_load(pc) {
+                    Ok(c) => lines.push(Line { pc, len: 1, text: format!("    db ${:02X}", c), kind: LineKind::Data }),
+                    Err(e) => {
+                        // nothing past this point in the bank can be shown either
+                        lines.push(Line { pc, len: 0, text: format!("    ; {}", e), kind: LineKind::Data });
+                        break;
+                    }
+                }
                 rpc += 1;
             }
         }
         lines
     }
-    pub fn process(&mut self, entry: QueueEntry) -> &Subroutine {
+    pub fn process(&mut self, mut entry: QueueEntry) -> &Subroutine {
         let orig_pc = entry.pc;
+        if let Some(c) = self.entry_states.get(&orig_pc) {
+            c.apply(&mut entry.state);
+        }
+        // other ways in are merged by dataflow
         if self.subroutines.contains_key(&orig_pc) {
             return self.subroutines.get(&orig_pc).unwrap()
         }
-        if orig_pc & 0x8000 != 0x8000 || orig_pc & 0x7FFFFF > 0x400000 {
-            return self.subroutines.entry(orig_pc).or_insert(Subroutine { sr_effect: Default::default(), divergent: false });
+        let divergent = self.no_return.contains(&orig_pc) || self.dispatchers.contains_key(&orig_pc);
+        if !self.rom.is_rom(orig_pc) {
+            return self.subroutines.entry(orig_pc).or_insert(Subroutine { divergent, ..Default::default() });
         }
         let mut queue = vec![entry];
-        let mut sr_effect = SrCpuState { affect_m: None, affect_x: None };
-        // this should really be in rules, not hardcoded
-        let mut divergent = false;
-        if orig_pc == 0x86DF || orig_pc == 0x86FA { divergent = true; }
+        let mut sr_effect = SrCpuState::default();
         'outer: while let Some(QueueEntry { mut pc, mut stack, mut state, mut sr_state }) = queue.pop() {
-            if pc & 0x8000 != 0x8000 || pc & 0x7FFFFF > 0x400000 {
+            if !self.rom.is_rom(pc) {
                 continue;
             }
             self.labels.insert(pc);
-            while let Some((size, instr)) = cpu::parse_instr(self.rom.slice(pc), state) {
-                use Mnemonic::*;
-                'inner: for i in pc..pc+size as u32 {
-                    if let Some(c) = self.entries.get(&i) {
-                        // TODO: figure out sr_effect
-                        if i == pc {
-                            if c.subroutine == orig_pc {
-                                // do nothing
-                            } else if let Some(sub) = self.subroutines.get(&c.subroutine) {
-                                if let Some(c) = sub.sr_effect.affect_m { sr_effect.affect_m = Some(c); state.m = c; }
-                                if let Some(c) = sub.sr_effect.affect_x { sr_effect.affect_x = Some(c); state.x = c; }
-                                self.subroutines.insert(orig_pc, sub.clone());
-                            } else {
-                                // do nothing
-                            }
-                        } else {
-                            eprintln!("WARN: bad instr at {:06X}", pc);
+            loop {
+                if let Some(&(m, x, e)) = self.hints.get(&pc) {
+                    state.m = m;
+                    state.x = x;
+                    state.e = e;
+                }
+                if let Some(c) = self.forced_states.get(&pc) {
+                    c.apply(&mut state);
+                }
+                // decoded already, dataflow merges this path's state in later
+                if let Some(c) = self.entries.get(&pc) {
+                    if c.subroutine != orig_pc {
+                        // the rest belongs to another subroutine, and so does what it does to the state
+                        if let Some(sub) = self.subroutines.get(&c.subroutine) {
+                            sr_effect = sr_effect.then(&sub.sr_effect);
                         }
-                        continue 'outer;
                     }
+                    continue 'outer;
+                }
+                let (size, instr) = match self.rom.try_slice(pc) {
+                    Ok(slice) => match cpu::parse_instr(slice, state) {
+                        Some(c) => c,
+                        None => break,
+                    },
+                    Err(e) => {
+                        self.report(Severity::Warning, pc, Some(orig_pc), Category::UnmappedTarget, format!("code runs into unmapped memory: {}", e));
+                        break;
+                    }
+                };
+                use Mnemonic::*;
+                if let Some(data) = self.data_at(pc, size as u32) {
+                    self.report(Severity::Warning, pc, Some(orig_pc), Category::DataRegion,
+                        format!("{} runs into data at {:06X}-{:06X}", instr, data.start, data.end - 1));
+                    continue 'outer;
+                }
+                if let Some(other) = (pc+1..pc+size as u32).find(|c| self.entries.contains_key(c)) {
+                    self.report(Severity::Warning, pc, Some(orig_pc), Category::OverlappingInstruction,
+                        format!("{} overlaps the instruction at {:06X}", instr, other));
+                    continue 'outer;
+                }
+                if matches!(instr.mnemonic, BRK|COP|STP|WDM) {
+                    self.report(Severity::Warning, pc, Some(orig_pc), Category::SuspiciousOpcode,
+                        format!("{:?} is unusual in code, this may be data", instr.mnemonic));
                 }
                 /*println!("{:06X} {:06X} {}{} {} {:?}", orig_pc, pc, 
                     if state.m { "M" } else { "m" },
                     if state.x { "X" } else { "x" },
                     instr, stack);*/
-                self.entries.insert(pc, Entry { stack: stack.clone(), state, instr, subroutine: orig_pc });
+                self.entries.insert(pc, Entry { stack: stack.clone(), state, instr, subroutine: orig_pc, m: state.m.into(), x: state.x.into() });
+                let emulation = state.emulation();
                 instr.apply_flags(&mut state);
-                instr.apply_flags_opt(&mut sr_state.affect_m, &mut sr_state.affect_x);
-                self.apply_instr(&instr, &mut state, &mut sr_state, &mut stack);
+                if !emulation {
+                    instr.apply_flags_opt(&mut sr_state.affect_m, &mut sr_state.affect_x);
+                }
+                if instr.mnemonic == XCE && state.emulation() {
+                    sr_state.affect_m = Some(true);
+                    sr_state.affect_x = Some(true);
+                }
+                self.apply_instr(pc, &instr, &mut state, &mut sr_state, &mut stack);
                 if matches!(instr.mnemonic, RTS|RTL) {
                     self.returns.insert(pc);
                     sr_effect = sr_state;
-                    divergent = false;
                 }
                 if let Some(target) = instr.jump_addr(pc) {
                     queue.push(QueueEntry { pc: target, stack: stack.clone(), state, sr_state });
@@ -215,9 +1084,11 @@ impl Disassembler {
                     let sr = self.process(QueueEntry { pc: target, stack: vec![StackDataType::RetAddr; size], state, sr_state: Default::default() });
                     if let Some(c) = sr.sr_effect.affect_m { sr_state.affect_m = Some(c); state.m = c; }
                     if let Some(c) = sr.sr_effect.affect_x { sr_state.affect_x = Some(c); state.x = c; }
+                    if let Some(c) = sr.sr_effect.affect_db { sr_state.affect_db = Some(c); state.db = c; }
+                    if let Some(c) = sr.sr_effect.affect_d { sr_state.affect_d = Some(c); state.d = c; }
+                    state.a = None;
+                    state.b = None;
                     if sr.divergent {
-                        divergent = false;
-                        self.extra_rules.push(Rule::JumpTable { pc, size: 0, long: target == 0x0086FA });
                         break;
                     }
                 }
@@ -226,9 +1097,155 @@ impl Disassembler {
                 pc += size as u32;
             }
         }
-        let sr = Subroutine { sr_effect, divergent };
+        let sr = Subroutine { sr_effect, divergent, blocks: vec![] };
         //println!("${:06X} ;{:?}", orig_pc, sr);
-        self.subroutines.entry(orig_pc).or_insert(sr)
+        // anything that got here first was a recursive call in the middle of this
+        self.subroutines.insert(orig_pc, sr);
+        &self.subroutines[&orig_pc]
+    }
+    /// The data region overlapping `size` bytes at `pc`, if any.
+    pub fn data_at(&self, pc: u32, size: u32) -> Option<Range<u32>> {
+        let offset = self.rom.try_map_rom(pc).ok()?;
+        self.data_regions.iter()
+            .find(|(_, c)| c.offsets.start < offset + size as usize && offset < c.offsets.end)
+            .map(|(&start, c)| start..c.end)
+    }
+    /// One line of typed data at `pc`, and how many bytes it covers. Lines
+    /// end at labels so they still get shown.
+    fn data_line(&self, pc: u32, region: &DataRegion) -> Option<(String, usize)> {
+        use std::fmt::Write;
+        let end = region.end.min((pc | 0xFFFF) + 1);
+        let labeled = |c: u32| self.labels.contains(&c) || self.label_names.contains_key(&c);
+        let size = region.kind.item_size();
+        let mut out = String::new();
+        let mut items = 0;
+        let mut len = 0;
+        match &region.kind {
+            DataKind::Ascii | DataKind::Text { .. } => {
+                out.push_str("    db ");
+                let mut quoted = false;
+                while items < region.per_line && pc + len < end && (len == 0 || !labeled(pc + len)) {
+                    let c = self.rom.try_load(pc + len).ok()?;
+                    let ch = match &region.kind {
+                        DataKind::Text { table } => self.text_tables.get(table).and_then(|t| t.get(&c)).cloned(),
+                        _ => (0x20..0x7F).contains(&c).then(|| (c as char).to_string()),
+                    };
+                    match ch.filter(|c| !c.contains('"')) {
+                        Some(ch) => {
+                            if !quoted {
+                                if len > 0 { out.push_str(", "); }
+                                out.push('"');
+                                quoted = true;
+                            }
+                            out.push_str(&ch);
+                        }
+                        None => {
+                            if quoted {
+                                out.push('"');
+                                quoted = false;
+                            }
+                            if len > 0 { out.push_str(", "); }
+                            write!(out, "${:02X}", c).ok()?;
+                        }
+                    }
+                    items += 1;
+                    len += 1;
+                }
+                if quoted { out.push('"'); }
+            }
+            kind => {
+                out.push_str(["    db ", "    dw ", "    dl ", "    dd "][size - 1]);
+                while items < region.per_line && pc + len + size as u32 <= end && (len == 0 || !labeled(pc + len)) {
+                    let mut value = 0u32;
+                    for i in 0..size as u32 {
+                        value |= (self.rom.try_load(pc + len + i).ok()? as u32) << (i * 8);
+                    }
+                    if len > 0 { out.push_str(", "); }
+                    let target = match kind {
+                        DataKind::WordPtrs => Some(value | (pc & 0xFF0000)),
+                        DataKind::LongPtrs => Some(value),
+                        _ => None,
+                    };
+                    match target {
+                        Some(c) if self.entries.contains_key(&c) || self.labels.contains(&c) => out.push_str(&self.get_label(c)),
+                        Some(c) => out.push_str(&self.get_data_label(c)),
+                        None => write!(out, "${:0width$X}", value, width = size * 2).ok()?,
+                    }
+                    items += 1;
+                    len += size as u32;
+                }
+            }
+        }
+        if len == 0 { None } else { Some((out, len as usize)) }
+    }
+    fn report(&mut self, severity: Severity, pc: u32, subroutine: Option<u32>, category: Category, message: String) {
+        // code gets decoded again when the state changes, which would repeat its problems
+        if !self.reported.insert((pc, category, message.clone())) {
+            return;
+        }
+        self.diagnostics.push(Diagnostic { severity, pc, subroutine, category, message });
+    }
+    fn record_xrefs(&mut self, pc: u32, instr: &Instruction, state: &CpuState) {
+        if let Some((target, kind)) = self.xref_of(pc, instr, state) {
+            self.add_xref(target, Xref { kind, from: pc, instr: *instr });
+        }
+    }
+    fn xref_of(&self, pc: u32, instr: &Instruction, state: &CpuState) -> Option<(u32, XrefKind)> {
+        use Mnemonic::*;
+        use cpu::Mode::*;
+        let kind = match (instr.mnemonic, instr.mode) {
+            (_, Imm|Imp|Sr|Isy|Bm) => return None,
+            (JMP|JML|JSR, Ind|Iax|Ial) => XrefKind::Pointer,
+            (JSR|JSL, _) => XrefKind::Call,
+            (JMP|JML|BRA|BRL, _) => XrefKind::Jump,
+            (BCC|BCS|BEQ|BNE|BMI|BPL|BVC|BVS, _) => XrefKind::Branch,
+            (PEA|PEI|PER, _) | (_, Idp|Idx|Idy|Idl|Ily) => XrefKind::Pointer,
+            (STA|STX|STY|STZ, _) => XrefKind::Write,
+            (INC|DEC|ASL|LSR|ROL|ROR|TSB|TRB, _) => XrefKind::ReadModifyWrite,
+            _ => XrefKind::Read,
+        };
+        let target = match kind {
+            XrefKind::Call | XrefKind::Jump | XrefKind::Branch => instr.jump_target(pc),
+            _ => instr.label_target(pc, state),
+        };
+        target.map(|c| (c, kind))
+    }
+    fn add_xref(&mut self, target: u32, xref: Xref) {
+        let target = self.normalize_addr(target);
+        self.xrefs.entry(target).or_default().push(xref);
+    }
+    /// Everything known to refer to `addr` or one of its mirrors.
+    pub fn xrefs_to(&self, addr: u32) -> Vec<Xref> {
+        let addr = self.normalize_addr(addr);
+        // ROM is kept under the address it's used with, so the other ways
+        // of reaching the same bytes have to be looked up too
+        let mut addrs = match self.rom.memory_map().classify(addr) {
+            Area::Rom { offset } => self.rom.mirrors(offset),
+            _ => vec![],
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        addrs.iter().filter_map(|c| self.xrefs.get(c)).flatten().copied().collect()
+    }
+    /// The references made by the instruction at `pc`, with their targets.
+    pub fn xrefs_from(&self, pc: u32) -> Vec<(u32, Xref)> {
+        self.xrefs.iter()
+            .flat_map(|(&target, c)| c.iter().filter(|c| c.from == pc).map(move |c| (target, *c)))
+            .collect()
+    }
+    /// Calls, jumps and branches to the code at `addr`.
+    pub fn callers(&self, addr: u32) -> impl Iterator<Item=Xref> {
+        self.xrefs_to(addr).into_iter().filter(|c| matches!(c.kind, XrefKind::Call | XrefKind::Jump | XrefKind::Branch | XrefKind::JumpTable))
+    }
+    /// The "; XREF: N callers" note shown after a label, or nothing if
+    /// there are none.
+    pub fn xref_hint(&self, addr: u32) -> String {
+        match self.callers(addr).count() {
+            0 => String::new(),
+            1 => " ; XREF: 1 caller".into(),
+            n => format!(" ; XREF: {} callers", n),
+        }
     }
     pub fn get_label(&self, addr: u32) -> String {
         if let Some(v) = self.label_names.get(&addr) {
@@ -241,46 +1258,56 @@ impl Disassembler {
             format!("loc_{:06X}", addr)
         }
     }
-    pub fn normalize_addr(&self, mut addr: u32) -> u32 {
-        if addr & 0xFFFF < 0x2000 && (addr >> 16) & 0x7F < 0x40 {
-            addr = (addr & 0xFFFF) | 0x7E0000;
-        }
-        if addr & 0xFFFF >= 0x2000 && addr & 0xFFFF < 0x8000 {
-            addr = addr & 0xFFFF;
-        }
-        addr
+    pub fn normalize_addr(&self, addr: u32) -> u32 {
+        self.rom.memory_map().canonical(addr)
     }
-    pub fn get_data_label(&self, mut addr: u32) -> String {
-        addr = self.normalize_addr(addr);
+    pub fn get_data_label(&self, addr: u32) -> String {
+        let addr = self.normalize_addr(addr);
         if let Some(v) = self.label_names.get(&addr) {
-            v.to_string()
-        } else if addr >= 0x7E2000 {
-            format!("wram_{:06X}", addr)
-        } else if addr >= 0x7E0000 {
-            format!("wram_{:04X}", addr & 0xFFFF)
-        } else if addr >= 0x700000 {
-            format!("sram_{:06X}", addr)
-        } else if addr & 0xFFFF >= 0x2000 && addr & 0xFFFF < 0x8000 {
-            format!("reg_{:04X}", addr & 0xFFFF)
-        } else {
-            format!("data_{:06X}", addr)
+            return v.to_string();
+        }
+        match self.rom.memory_map().classify(addr) {
+            Area::Wram(c) if c >= 0x7E2000 => format!("wram_{:06X}", c),
+            Area::Wram(c) => format!("wram_{:04X}", c & 0xFFFF),
+            Area::Sram { .. } => format!("sram_{:06X}", addr),
+            Area::PpuRegister(c) | Area::CpuRegister(c) | Area::DmaRegister(c) => format!("reg_{:04X}", c),
+            Area::Coprocessor(c) | Area::Expansion(c) => format!("exp_{:04X}", c),
+            Area::Rom { .. } => format!("data_{:06X}", addr),
+            Area::OpenBus => format!("bus_{:06X}", addr),
         }
     }
     pub fn apply_instr(
         &mut self,
+        pc: u32,
         instr: &Instruction,
         state: &mut CpuState,
         sr_state: &mut SrCpuState,
         stack: &mut Vec<StackDataType>
     ) {
         use Mnemonic::*;
+        let byte = |c: Option<u8>| c.map(StackDataType::Bank).unwrap_or(StackDataType::Data);
+        let pop = |stack: &mut Vec<StackDataType>| match stack.pop() {
+            Some(StackDataType::Bank(c)) => Some(c),
+            _ => None,
+        };
+        let pulled = match instr.mnemonic {
+            PLA => if state.m { 1 } else { 2 },
+            PLX|PLY => if state.x { 1 } else { 2 },
+            PLB|PLP => 1,
+            PLD => 2,
+            _ => 0,
+        };
+        if stack.len() < pulled {
+            let sub = self.entries.get(&pc).map(|c| c.subroutine);
+            self.report(Severity::Warning, pc, sub, Category::StackUnderflow, format!("{} pulls more than was pushed", instr));
+        }
         match instr.mnemonic {
             PHP => stack.push(StackDataType::CpuState { state: *state, sr_state: *sr_state }),
             PHA => if state.m {
-                stack.push(StackDataType::Data);
+                stack.push(byte(state.a));
             } else {
-                stack.push(StackDataType::Data);
-                stack.push(StackDataType::Data);
+                stack.push(byte(state.b));
+                stack.push(byte(state.a));
             }
             PHX|PHY => if state.x {
                 stack.push(StackDataType::Data);
@@ -288,20 +1315,43 @@ impl Disassembler {
                 stack.push(StackDataType::Data);
                 stack.push(StackDataType::Data);
             }
-            PEA|PER|PEI|PHD => {
+            PEA => {
+                stack.push(StackDataType::Bank((instr.argument >> 8) as u8));
+                stack.push(StackDataType::Bank(instr.argument as u8));
+            }
+            PER|PEI => {
                 stack.push(StackDataType::Data);
                 stack.push(StackDataType::Data);
             }
-            PHK|PHB => stack.push(StackDataType::Data),
+            PHD => {
+                let saved = StackDataType::SavedD { d: state.d, affect_d: sr_state.affect_d };
+                stack.push(saved.clone());
+                stack.push(saved);
+            }
+            PHK => stack.push(StackDataType::Bank((pc >> 16) as u8)),
+            PHB => stack.push(StackDataType::SavedDb { db: state.db, affect_db: sr_state.affect_db }),
             PLP => match stack.pop() {
-                Some(StackDataType::CpuState { state: s, sr_state: r }) => { *state = s; *sr_state = r },
-                _ => eprintln!("uh oh bad PLP"),
+                Some(StackDataType::CpuState { state: s, sr_state: r }) => {
+                    // only the flags come back, and PLP can't leave emulation
+                    // mode or clear M/X in it
+                    state.m = s.m || state.emulation();
+                    state.x = s.x || state.emulation();
+                    state.carry = s.carry;
+                    // DB and D aren't in P, so PLP leaves what happened to them alone
+                    *sr_state = SrCpuState { affect_db: sr_state.affect_db, affect_d: sr_state.affect_d, ..r };
+                },
+                // already reported as an underflow
+                None => {}
+                _ => {
+                    let sub = self.entries.get(&pc).map(|c| c.subroutine);
+                    self.report(Severity::Warning, pc, sub, Category::UnknownFlagState, "PLP pulls something PHP didn't push, keeping M and X".into());
+                }
             },
             PLA => if state.m {
-                stack.pop();
+                state.a = pop(stack);
             } else {
-                stack.pop();
-                stack.pop();
+                state.a = pop(stack);
+                state.b = pop(stack);
             }
             PLX|PLY => if state.x {
                 stack.pop();
@@ -310,10 +1360,71 @@ impl Disassembler {
                 stack.pop();
             }
             PLD => {
-                stack.pop();
-                stack.pop();
+                match (stack.pop(), stack.pop()) {
+                    (Some(StackDataType::SavedD { d, affect_d }), Some(StackDataType::SavedD { .. })) => {
+                        state.d = d;
+                        sr_state.affect_d = affect_d;
+                    }
+                    (Some(StackDataType::Bank(lo)), Some(StackDataType::Bank(hi))) => {
+                        state.d = Some(u16::from_le_bytes([lo, hi]));
+                        sr_state.affect_d = Some(state.d);
+                    }
+                    _ => {
+                        state.d = None;
+                        sr_state.affect_d = Some(None);
+                    }
+                }
+            }
+            TCD => {
+                state.d = state.a.zip(state.b).map(|(lo, hi)| u16::from_le_bytes([lo, hi]));
+                sr_state.affect_d = Some(state.d);
+            }
+            TDC => {
+                state.a = state.d.map(|c| c as u8);
+                state.b = state.d.map(|c| (c >> 8) as u8);
+            }
+            PLB => {
+                match stack.pop() {
+                    // restoring what PHB saved undoes any change in between
+                    Some(StackDataType::SavedDb { db, affect_db }) => {
+                        state.db = db;
+                        sr_state.affect_db = affect_db;
+                    }
+                    Some(StackDataType::Bank(c)) => {
+                        state.db = Some(c);
+                        sr_state.affect_db = Some(Some(c));
+                    }
+                    _ => {
+                        state.db = None;
+                        sr_state.affect_db = Some(None);
+                    }
+                }
+            }
+            MVN|MVP => {
+                // the destination bank is the first operand byte
+                state.db = Some(instr.argument as u8);
+                sr_state.affect_db = state.db.map(Some);
+                // the count in C runs down to $FFFF
+                state.a = Some(0xFF);
+                state.b = Some(0xFF);
+            }
+            LDA if instr.mode == cpu::Mode::Imm => {
+                state.a = Some(instr.argument as u8);
+                if instr.size == 2 { state.b = Some((instr.argument >> 8) as u8); }
+            }
+            XBA => std::mem::swap(&mut state.a, &mut state.b),
+            LDA | ADC | SBC | AND | EOR | ORA | TXA | TYA => {
+                state.a = None;
+                if !state.m { state.b = None; }
+            }
+            INC | DEC | ASL | LSR | ROL | ROR if instr.mode == cpu::Mode::Imp => {
+                state.a = None;
+                if !state.m { state.b = None; }
+            }
+            TSC => {
+                state.a = None;
+                state.b = None;
             }
-            PLB => { stack.pop(); },
             _ => {}
         }
     }
@@ -1,8 +1,11 @@
 use glow::HasContext;
 use serde_derive::{Serialize, Deserialize};
 use crate::dis;
-use crate::rom::Rom;
+use crate::header::{self, CartridgeHeader};
+use crate::patch::{Patch, PatchError, PatchFormat};
+use crate::rom::{Checksum, Mapper, Normalization, Rom, RomImage};
 use std::collections::HashMap;
+use std::ops::Range;
 
 /*
 pub struct Driver {
@@ -281,8 +284,14 @@ impl Driver {
 
 pub struct GlobalState {
     pub rom: Rom,
+    pub header: Option<CartridgeHeader>,
+    /// Checksum of the ROM as loaded, if it has a header
+    pub checksum: Option<Checksum>,
+    /// Whether to update the header checksum in exported patches
+    pub fix_checksum: bool,
     pub dis: dis::Disassembler,
     pub rules: Vec<dis::Rule>,
+    /// Addresses of the line the selection started on and the one it ends on
     pub selection: Option<[u32;2]>,
     pub lines: Vec<dis::Line>,
     pub comments: HashMap<u32, HashMap<usize, String>>,
@@ -291,6 +300,18 @@ pub struct GlobalState {
     pub bank: u8,
     // should be PathBuf probably
     pub rules_filename: String,
+    pub rom_filename: String,
+    /// The ROM image before any patches, copier header included
+    pub original: Vec<u8>,
+    /// Copier header stripped from the image, if any
+    pub copier_header: Vec<u8>,
+    /// What had to be done to the dump to get a linear image
+    pub normalization: Vec<Normalization>,
+    pub patches: Vec<String>,
+    /// ROM offsets written by any of the patches
+    pub patched: Vec<Range<usize>>,
+    /// Why the patches weren't applied, if one of them failed
+    pub patch_error: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -298,22 +319,98 @@ pub struct SavedData {
     rules: Vec<dis::Rule>,
     comments: HashMap<u32, HashMap<usize, String>>,
     label_names: HashMap<u32, String>,
+    /// IPS/BPS patches applied to the ROM file on load, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    patches: Vec<String>,
+}
+
+/// Finds the files a dump was split into: `game.1`, `game.2`, ... if
+/// `fname` is the first one, or just `fname` otherwise.
+fn split_parts(fname: &str) -> Vec<String> {
+    let path = std::path::Path::new(fname);
+    if path.extension().map_or(true, |c| c != "1") {
+        return vec![fname.to_string()];
+    }
+    let rest = (2..).map(|i| path.with_extension(i.to_string()))
+        .take_while(|c| c.exists())
+        .map(|c| c.to_string_lossy().into_owned());
+    std::iter::once(fname.to_string()).chain(rest).collect()
+}
+
+/// Applies a patch to an image with `header` bytes of copier header in
+/// front, returning the new image and the offsets it wrote. BPS patches are
+/// made against headerless ROMs by convention, so they only get the header
+/// if they don't match without it.
+fn apply_patch(patch: &Patch, image: &[u8], header: usize) -> Result<(Vec<u8>, Vec<Range<usize>>), PatchError> {
+    if matches!(patch, Patch::Bps(_)) && header > 0 {
+        match patch.apply(&image[header..]) {
+            Ok(cart) => {
+                let mut out = image[..header].to_vec();
+                out.extend(cart);
+                let touched = patch.touched().into_iter().map(|c| c.start + header..c.end + header).collect();
+                return Ok((out, touched));
+            }
+            Err(e @ (PatchError::SourceCrc { .. } | PatchError::SourceSize { .. })) => {
+                return patch.apply(image).map(|c| (c, patch.touched())).map_err(|_| e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((patch.apply(image)?, patch.touched()))
 }
 
 impl GlobalState {
     pub fn new(rom_fname: &str, rules_fname: &str) -> Self {
-        let mut rom_bytes = std::fs::read(rom_fname).unwrap();
-        if rom_bytes.len() % 0x400 == 0x200 {
-            rom_bytes.drain(..0x200);
+        let data: SavedData = serde_yaml::from_slice(&std::fs::read(rules_fname).unwrap()).unwrap();
+        let parts = split_parts(rom_fname).iter().map(|c| std::fs::read(c).unwrap()).collect();
+        let image = RomImage::normalize(parts);
+        let copier_header = image.copier_header;
+        // patches apply to the linear image, with the copier header if it
+        // had one, except for BPS (see apply_patch)
+        let mut original = copier_header.clone();
+        original.extend(image.cart);
+        let mut rom_bytes = original.clone();
+        let mut patched = vec![];
+        let mut patch_error = None;
+        for fname in data.patches.iter() {
+            let result = std::fs::read(fname).map_err(|e| e.to_string())
+                .and_then(|c| Patch::parse(&c).map_err(|e| e.to_string()))
+                .and_then(|c| apply_patch(&c, &rom_bytes, copier_header.len()).map_err(|e| e.to_string()));
+            match result {
+                Ok((bytes, touched)) => {
+                    rom_bytes = bytes;
+                    patched.extend(touched);
+                }
+                Err(e) => {
+                    // half of the patches is worse than none of them
+                    patch_error = Some(format!("Couldn't apply {}: {}, showing the unpatched ROM", fname, e));
+                    rom_bytes = original.clone();
+                    patched.clear();
+                    break;
+                }
+            }
         }
-        let rom = Rom::new(rom_bytes, crate::rom::Mapper::LoRom);
+        let skip = copier_header.len().min(rom_bytes.len());
+        rom_bytes.drain(..skip);
+        let patched = patched.into_iter()
+            .filter(|c| c.end > skip)
+            .map(|c| c.start.saturating_sub(skip)..c.end - skip)
+            .collect();
+        let header = header::detect(&rom_bytes);
+        let mapper = header.as_ref().map(|c| c.mapper).unwrap_or(Mapper::LoRom);
+        let mut rom = Rom::new(rom_bytes, mapper);
+        rom.sram_size = header.as_ref().map(|c| c.sram_size).unwrap_or(0);
+        // a mismatch shows up in the ROM info window
+        let checksum = header.as_ref().and_then(|c| rom.verify_checksum(c.offset));
         let mut dis = dis::Disassembler::new(rom.clone());
-        let data: SavedData = serde_yaml::from_slice(&std::fs::read(rules_fname).unwrap()).unwrap();
         dis.label_names = data.label_names;
         dis.process_rules(data.rules.iter());
         let lines = dis.print_bank(0);
         Self {
             rom,
+            header,
+            checksum,
+            fix_checksum: false,
             dis,
             rules: data.rules,
             selection: None,
@@ -323,6 +420,13 @@ impl GlobalState {
             comments: data.comments,
             bank: 0,
             rules_filename: rules_fname.to_string(),
+            rom_filename: rom_fname.to_string(),
+            original,
+            copier_header,
+            normalization: image.steps,
+            patches: data.patches,
+            patched,
+            patch_error,
         }
     }
     pub fn save(&mut self) {
@@ -333,13 +437,111 @@ impl GlobalState {
             rules: self.rules.clone(),
             comments: self.comments.clone(),
             label_names: self.dis.label_names.clone(),
+            patches: self.patches.clone(),
         }).unwrap();
         // TODO: error reporting
         std::fs::write(&self.rules_filename, &b).unwrap();
     }
+    /// Where a patch goes unless the user picks something else: next to
+    /// the ROM file.
+    pub fn default_patch_name(&self, format: PatchFormat) -> String {
+        std::path::Path::new(&self.rom_filename)
+            .with_extension(format.extension())
+            .to_string_lossy().into_owned()
+    }
+    /// Writes a patch from the original ROM file to the current ROM. Files
+    /// the project is loaded from are never written over, and anything else
+    /// only if `replace` is set.
+    pub fn export_patch(&self, format: PatchFormat, fname: &str, replace: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::path::Path::new(fname);
+        if path.exists() {
+            let target = std::fs::canonicalize(path)?;
+            let inputs = split_parts(&self.rom_filename).into_iter()
+                .chain(self.patches.iter().cloned())
+                .chain(std::iter::once(self.rules_filename.clone()));
+            for c in inputs {
+                if std::fs::canonicalize(&c).map_or(false, |c| c == target) {
+                    return Err(format!("{} is one of the project's input files", fname).into());
+                }
+            }
+            if !replace {
+                return Err(format!("{} already exists", fname).into());
+            }
+        }
+        // like on loading, BPS patches leave the copier header out
+        let header = match format {
+            PatchFormat::Bps => self.copier_header.len(),
+            PatchFormat::Ips => 0,
+        };
+        let patch = Patch::create(format, &self.original[header..], &self.image()[header..])?;
+        std::fs::write(path, patch.to_bytes())?;
+        Ok(())
+    }
+    /// The current ROM as it would be written to a file.
+    pub fn image(&self) -> Vec<u8> {
+        let mut rom = self.rom.clone();
+        if let Some(header) = self.header.as_ref().filter(|_| self.fix_checksum) {
+            rom.fix_checksum(header.offset);
+        }
+        let mut image = self.copier_header.clone();
+        image.extend_from_slice(rom.cart());
+        image
+    }
     pub fn update_lines(&mut self) {
         self.lines = self.dis.print_bank(self.bank as _);
     }
+    /// Bytes covered by the selected lines.
+    pub fn selected_range(&self) -> Option<Range<u32>> {
+        let [a, b] = self.selection?;
+        let lines = self.lines.iter().filter(|c| c.len > 0 && (a.min(b)..=a.max(b)).contains(&c.pc));
+        lines.fold(None, |r: Option<Range<u32>>, c| {
+            let end = c.pc + c.len as u32;
+            Some(r.map_or(c.pc..end, |r| r.start.min(c.pc)..r.end.max(end)))
+        })
+    }
+    /// Replaces what the rules say about `range` with `rules`, keeping the
+    /// parts of data regions outside of it, and analyzes the ROM again.
+    pub fn retype(&mut self, range: Range<u32>, rules: Vec<dis::Rule>) {
+        use dis::Rule;
+        for rule in std::mem::take(&mut self.rules) {
+            match rule {
+                Rule::Data { start, end, kind, per_line } if start < range.end && range.start < end => {
+                    if start < range.start {
+                        self.rules.push(Rule::Data { start, end: range.start, kind: kind.clone(), per_line });
+                    }
+                    if range.end < end {
+                        self.rules.push(Rule::Data { start: range.end, end, kind, per_line });
+                    }
+                }
+                Rule::Entry { pc, .. } | Rule::JumpTable { pc, .. } if range.contains(&pc) => {}
+                c => self.rules.push(c),
+            }
+        }
+        self.rules.extend(rules);
+        self.reanalyze();
+    }
+    /// Runs the analysis again from scratch, after the rules changed.
+    pub fn reanalyze(&mut self) {
+        let mut dis = dis::Disassembler::new(self.rom.clone());
+        dis.label_names = std::mem::take(&mut self.dis.label_names);
+        dis.process_rules(self.rules.iter());
+        self.dis = dis;
+        self.update_lines();
+    }
+    pub fn is_patched(&self, addr: u32) -> bool {
+        match self.dis.rom.try_map_rom(addr) {
+            Ok(off) => self.patched.iter().any(|c| c.contains(&off)),
+            Err(_) => false,
+        }
+    }
+    /// Finds the SNES address for a file offset, preferring whichever
+    /// mirror the disassembly actually uses.
+    pub fn resolve_offset(&self, off: usize) -> Option<u32> {
+        let rom = &self.dis.rom;
+        rom.mirrors(off).into_iter()
+            .find(|c| self.dis.entries.contains_key(c) || self.dis.labels.contains(c))
+            .or_else(|| rom.unmap_rom(off))
+    }
     /*
     fn disassemble(&mut self, start: u32) {
         self.dis.process(start);
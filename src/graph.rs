@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use egui::{pos2, vec2, Pos2, Rect, Vec2};
+
+use crate::dis::{Disassembler, EdgeKind};
+
+const MARGIN: f32 = 16.0;
+const H_GAP: f32 = 32.0;
+const V_GAP: f32 = 48.0;
+/// Horizontal distance between back edges running up the right side
+const BACK_EDGE_GAP: f32 = 8.0;
+const SWEEPS: usize = 4;
+
+/// Block positions and edge routes for drawing a subroutine's control flow
+/// graph, in a coordinate system with the origin at the top left.
+#[derive(Clone, Debug, Default)]
+pub struct GraphLayout {
+    pub blocks: HashMap<u32, Rect>,
+    pub edges: Vec<GraphEdge>,
+    pub size: Vec2,
+}
+
+#[derive(Clone, Debug)]
+pub struct GraphEdge {
+    pub from: u32,
+    pub to: u32,
+    pub kind: EdgeKind,
+    /// Whether the edge goes back up to an earlier layer (a loop)
+    pub back: bool,
+    /// Polyline from the bottom of the source block to the top of the target
+    pub points: Vec<Pos2>,
+}
+
+/// Lays out the basic blocks of a subroutine in layers, top to bottom, with
+/// `block_size` giving the size of each block's box.
+pub fn layout(dis: &Disassembler, subroutine: u32, block_size: impl Fn(u32) -> Vec2) -> GraphLayout {
+    let Some(sub) = dis.subroutines.get(&subroutine) else { return Default::default() };
+    let mut starts = sub.blocks.clone();
+    // the entry block goes first so the search for loops starts there
+    if let Some(idx) = starts.iter().position(|&c| c == subroutine) {
+        starts[..=idx].rotate_right(1);
+    }
+    let index: HashMap<u32, usize> = starts.iter().enumerate().map(|(i, &c)| (c, i)).collect();
+    let n = starts.len();
+    let edges = starts.iter()
+        .flat_map(|c| dis.blocks[c].successors.iter())
+        .filter_map(|e| Some((index[&e.from], *index.get(&e.to)?, e.kind)))
+        .collect::<Vec<_>>();
+
+    // depth-first search, edges into a block that's still on the stack close a loop
+    let mut back = vec![false; edges.len()];
+    let mut outgoing = vec![vec![]; n];
+    for (i, &(from, _, _)) in edges.iter().enumerate() {
+        outgoing[from].push(i);
+    }
+    let mut visit = vec![0u8; n];
+    for root in 0..n {
+        if visit[root] != 0 { continue; }
+        visit[root] = 1;
+        let mut stack = vec![(root, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            if let Some(&e) = outgoing[node].get(*next) {
+                *next += 1;
+                let to = edges[e].1;
+                match visit[to] {
+                    0 => { visit[to] = 1; stack.push((to, 0)); }
+                    1 => back[e] = true,
+                    _ => {}
+                }
+            } else {
+                visit[node] = 2;
+                stack.pop();
+            }
+        }
+    }
+
+    // longest path layering of what's left, which is acyclic
+    let mut layer = vec![0usize; n];
+    let mut in_degree = vec![0usize; n];
+    for (i, &(_, to, _)) in edges.iter().enumerate() {
+        if !back[i] { in_degree[to] += 1; }
+    }
+    let mut queue = (0..n).filter(|&c| in_degree[c] == 0).collect::<Vec<_>>();
+    while let Some(node) = queue.pop() {
+        for &e in outgoing[node].iter().filter(|&&e| !back[e]) {
+            let to = edges[e].1;
+            layer[to] = layer[to].max(layer[node] + 1);
+            in_degree[to] -= 1;
+            if in_degree[to] == 0 { queue.push(to); }
+        }
+    }
+
+    // edges spanning several layers go through invisible nodes, one per layer
+    let mut sizes = starts.iter().map(|&c| block_size(c)).collect::<Vec<_>>();
+    let mut chains = vec![];
+    for (i, &(from, to, _)) in edges.iter().enumerate() {
+        let mut chain = vec![from];
+        if !back[i] {
+            for l in layer[from] + 1..layer[to] {
+                chain.push(sizes.len());
+                sizes.push(Vec2::ZERO);
+                layer.push(l);
+            }
+        }
+        chain.push(to);
+        chains.push(chain);
+    }
+    let nodes = sizes.len();
+    let mut up = vec![vec![]; nodes];
+    let mut down = vec![vec![]; nodes];
+    for (chain, _) in chains.iter().zip(back.iter()).filter(|(_, &b)| !b) {
+        for pair in chain.windows(2) {
+            down[pair[0]].push(pair[1]);
+            up[pair[1]].push(pair[0]);
+        }
+    }
+    let num_layers = layer.iter().max().map_or(0, |c| c + 1);
+    let mut layers = vec![vec![]; num_layers];
+    for node in 0..nodes {
+        layers[layer[node]].push(node);
+    }
+
+    // reduce crossings by ordering each layer by the average position of its
+    // neighbours, alternating between looking up and down
+    let mut pos = vec![0.0f32; nodes];
+    for nodes in layers.iter() {
+        for (i, &node) in nodes.iter().enumerate() { pos[node] = i as f32; }
+    }
+    for sweep in 0..SWEEPS {
+        let (order, neighbours) = if sweep % 2 == 0 {
+            ((1..num_layers).collect::<Vec<_>>(), &up)
+        } else {
+            ((0..num_layers.saturating_sub(1)).rev().collect(), &down)
+        };
+        for l in order {
+            let key = |node: usize| {
+                let adj = &neighbours[node];
+                if adj.is_empty() { pos[node] } else { adj.iter().map(|&c| pos[c]).sum::<f32>() / adj.len() as f32 }
+            };
+            let mut keyed = layers[l].iter().map(|&c| (key(c), c)).collect::<Vec<_>>();
+            keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+            layers[l] = keyed.into_iter().map(|c| c.1).collect();
+            for (i, &node) in layers[l].iter().enumerate() { pos[node] = i as f32; }
+        }
+    }
+
+    // vertical position comes from the layer, horizontal from trying to sit
+    // centered under the blocks above (or over the ones below), without overlapping
+    // leave room above and below for the back edges to turn around
+    let mut layer_y = vec![V_GAP / 2.0; num_layers];
+    let mut layer_h = vec![0.0f32; num_layers];
+    for l in 0..num_layers {
+        layer_h[l] = layers[l].iter().map(|&c| sizes[c].y).fold(0.0, f32::max);
+        if l + 1 < num_layers { layer_y[l + 1] = layer_y[l] + layer_h[l] + V_GAP; }
+    }
+    let mut center = vec![0.0f32; nodes];
+    for nodes in layers.iter() {
+        let mut x = 0.0;
+        for &node in nodes {
+            center[node] = x + sizes[node].x / 2.0;
+            x += sizes[node].x + H_GAP;
+        }
+    }
+    for pass in 0..3 {
+        let (order, neighbours) = if pass % 2 == 0 {
+            ((0..num_layers).collect::<Vec<_>>(), &up)
+        } else {
+            ((0..num_layers).rev().collect(), &down)
+        };
+        for l in order {
+            let mut right = f32::NEG_INFINITY;
+            for &node in layers[l].iter() {
+                let adj = &neighbours[node];
+                let wanted = if adj.is_empty() { center[node] } else { adj.iter().map(|&c| center[c]).sum::<f32>() / adj.len() as f32 };
+                let half = sizes[node].x / 2.0;
+                center[node] = wanted.max(right + H_GAP + half);
+                right = center[node] + half;
+            }
+        }
+    }
+    let left = (0..nodes).map(|c| center[c] - sizes[c].x / 2.0).fold(f32::INFINITY, f32::min);
+    for c in center.iter_mut() { *c += MARGIN - left; }
+    let rect = |node: usize| Rect::from_min_size(pos2(center[node] - sizes[node].x / 2.0, layer_y[layer[node]]), sizes[node]);
+    let width = (0..nodes).map(|c| rect(c).right()).fold(0.0, f32::max);
+
+    // edges leave from evenly spaced points along the bottom of their block
+    let exit_x = |e: usize| {
+        let from = edges[e].0;
+        let r = rect(from);
+        let k = outgoing[from].iter().position(|&c| c == e).unwrap();
+        r.left() + r.width() * (k + 1) as f32 / (outgoing[from].len() + 1) as f32
+    };
+    let mut out = vec![];
+    let mut back_edges = 0;
+    for (e, chain) in chains.iter().enumerate() {
+        let (from, to, kind) = edges[e];
+        let mut points = vec![pos2(exit_x(e), rect(from).bottom())];
+        if back[e] {
+            back_edges += 1;
+            let x = width + BACK_EDGE_GAP * back_edges as f32;
+            let below = layer_y[layer[from]] + layer_h[layer[from]] + V_GAP / 2.0 - BACK_EDGE_GAP / 2.0;
+            let above = layer_y[layer[to]] - V_GAP / 2.0 + BACK_EDGE_GAP / 2.0;
+            points.extend([pos2(points[0].x, below), pos2(x, below), pos2(x, above), pos2(rect(to).center().x, above)]);
+        } else {
+            for pair in chain.windows(2) {
+                let here = points.last().unwrap().x;
+                let l = layer[pair[0]];
+                let mid = layer_y[l] + layer_h[l] + V_GAP / 2.0;
+                let x = rect(pair[1]).center().x;
+                points.extend([pos2(here, mid), pos2(x, mid)]);
+                if pair[1] != to {
+                    // pass straight through the invisible node's layer
+                    points.extend([pos2(x, layer_y[l + 1]), pos2(x, layer_y[l + 1] + layer_h[l + 1])]);
+                }
+            }
+        }
+        points.push(pos2(rect(to).center().x, rect(to).top()));
+        out.push(GraphEdge { from: starts[from], to: starts[to], kind, back: back[e], points });
+    }
+
+    let height = layer_y.last().zip(layer_h.last()).map_or(0.0, |(y, h)| y + h + V_GAP / 2.0);
+    GraphLayout {
+        blocks: (0..n).map(|c| (starts[c], rect(c))).collect(),
+        edges: out,
+        size: vec2(width + BACK_EDGE_GAP * back_edges as f32 + MARGIN, height),
+    }
+}
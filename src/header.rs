@@ -0,0 +1,259 @@
+use crate::rom::Mapper;
+
+/// Offsets of the internal header candidates, for LoROM, HiROM and ExHiROM.
+pub const CANDIDATES: [usize; 3] = [0x7FC0, 0xFFC0, 0x40FFC0];
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    /// File offset of the header ($xxC0)
+    pub offset: usize,
+    pub title: String,
+    pub map_mode: u8,
+    pub mapper: Mapper,
+    pub fast_rom: bool,
+    pub chipset: Chipset,
+    /// ROM size in bytes, as declared by the header
+    pub rom_size: usize,
+    /// SRAM size in bytes, as declared by the header
+    pub sram_size: usize,
+    pub region: Region,
+    pub developer_id: u8,
+    /// Two-character maker code from the extended header, when the
+    /// developer ID is $33
+    pub maker_code: Option<String>,
+    pub game_code: Option<String>,
+    pub version: u8,
+    pub complement: u16,
+    pub checksum: u16,
+    pub reset_vector: u16,
+    pub nmi_vector: u16,
+    pub irq_vector: u16,
+    /// How plausible this header looked during detection
+    pub score: i32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Chipset {
+    pub ram: bool,
+    pub battery: bool,
+    pub coprocessor: Option<Coprocessor>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Coprocessor {
+    Dsp,
+    SuperFx,
+    Obc1,
+    Sa1,
+    Sdd1,
+    Srtc,
+    Spc7110,
+    St010,
+    St018,
+    Cx4,
+    Other(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Region {
+    Japan,
+    NorthAmerica,
+    Europe,
+    Scandinavia,
+    Finland,
+    Denmark,
+    France,
+    Netherlands,
+    Spain,
+    Germany,
+    Italy,
+    China,
+    Indonesia,
+    Korea,
+    International,
+    Canada,
+    Brazil,
+    Australia,
+    Other(u8),
+}
+
+impl Chipset {
+    pub fn parse(kind: u8, subtype: u8) -> Self {
+        let (ram, battery, coprocessor) = match kind & 0x0F {
+            0 => (false, false, false),
+            1 => (true, false, false),
+            2 => (true, true, false),
+            3 => (false, false, true),
+            4 => (true, false, true),
+            5 => (true, true, true),
+            6 => (false, true, true),
+            _ => (false, false, false),
+        };
+        let coprocessor = coprocessor.then(|| match kind >> 4 {
+            0x0 => Coprocessor::Dsp,
+            0x1 => Coprocessor::SuperFx,
+            0x2 => Coprocessor::Obc1,
+            0x3 => Coprocessor::Sa1,
+            0x4 => Coprocessor::Sdd1,
+            0x5 => Coprocessor::Srtc,
+            0xF => match subtype {
+                0x00 => Coprocessor::Spc7110,
+                0x01 => Coprocessor::St010,
+                0x02 => Coprocessor::St018,
+                0x10 => Coprocessor::Cx4,
+                c => Coprocessor::Other(c),
+            },
+            c => Coprocessor::Other(c),
+        });
+        Self { ram, battery, coprocessor }
+    }
+}
+
+impl Region {
+    pub fn parse(code: u8) -> Self {
+        use Region::*;
+        match code {
+            0x00 => Japan,
+            0x01 => NorthAmerica,
+            0x02 => Europe,
+            0x03 => Scandinavia,
+            0x04 => Finland,
+            0x05 => Denmark,
+            0x06 => France,
+            0x07 => Netherlands,
+            0x08 => Spain,
+            0x09 => Germany,
+            0x0A => Italy,
+            0x0B => China,
+            0x0C => Indonesia,
+            0x0D => Korea,
+            0x0E => International,
+            0x0F => Canada,
+            0x10 => Brazil,
+            0x11 => Australia,
+            c => Other(c),
+        }
+    }
+}
+
+impl CartridgeHeader {
+    /// Parses the header at `offset` ($xxC0) without judging whether it is
+    /// actually a header.
+    pub fn parse(cart: &[u8], offset: usize) -> Option<Self> {
+        let h = cart.get(offset..offset + 0x40)?;
+        let u16_at = |i: usize| u16::from_le_bytes([h[i], h[i+1]]);
+        let title = h[0x00..0x15].iter().map(|&c| match c {
+            0x20..=0x7E => c as char,
+            // JIS X 0201 half-width katakana
+            0xA1..=0xDF => char::from_u32(0xFF61 + (c - 0xA1) as u32).unwrap(),
+            _ => ' ',
+        }).collect::<String>().trim_end().to_string();
+        let map_mode = h[0x15];
+        let developer_id = h[0x1A];
+        // the extended header lives in the 16 bytes before the header proper
+        let ext = offset.checked_sub(0x10).and_then(|c| cart.get(c..offset));
+        let ascii = |b: &[u8]| -> Option<String> {
+            b.iter().all(|c| c.is_ascii_alphanumeric()).then(|| String::from_utf8_lossy(b).into_owned())
+        };
+        let (maker_code, game_code, subtype) = match ext {
+            Some(ext) if developer_id == 0x33 => (ascii(&ext[0..2]), ascii(&ext[2..6]), ext[0x0F]),
+            _ => (None, None, 0),
+        };
+        let size = |c: u8| if c == 0 || c > 0x10 { 0 } else { 0x400 << c };
+        Some(Self {
+            offset,
+            title,
+            map_mode,
+            mapper: Self::infer_mapper(offset, map_mode, cart.len()),
+            fast_rom: map_mode & 0x10 != 0,
+            chipset: Chipset::parse(h[0x16], subtype),
+            rom_size: size(h[0x17]),
+            sram_size: size(h[0x18]),
+            region: Region::parse(h[0x19]),
+            developer_id,
+            maker_code,
+            game_code,
+            version: h[0x1B],
+            complement: u16_at(0x1C),
+            checksum: u16_at(0x1E),
+            nmi_vector: u16_at(0x3A),
+            reset_vector: u16_at(0x3C),
+            irq_vector: u16_at(0x3E),
+            score: 0,
+        })
+    }
+    fn infer_mapper(offset: usize, map_mode: u8, len: usize) -> Mapper {
+        match (map_mode & 0x0F, offset) {
+            (0x0 | 0x2, 0x7FC0) if len > 0x400000 => Mapper::ExLoRom,
+            (0x0 | 0x2, _) => Mapper::LoRom,
+            (0x1 | 0xA, _) => Mapper::HiRom,
+            (0x3, _) => Mapper::Sa1,
+            (0x5, _) => Mapper::ExHiRom,
+            (_, 0x7FC0) => Mapper::LoRom,
+            (_, 0xFFC0) => Mapper::HiRom,
+            _ => Mapper::ExHiRom,
+        }
+    }
+    /// File offset of the code the reset vector points at, assuming this
+    /// header is the real one.
+    fn reset_offset(&self) -> usize {
+        match self.offset {
+            0x7FC0 => self.reset_vector as usize & 0x7FFF,
+            c => (c & !0xFFFF) | self.reset_vector as usize,
+        }
+    }
+    fn complement_fits(&self) -> bool {
+        self.checksum ^ self.complement == 0xFFFF
+    }
+    /// Whether the map mode is one that puts the header where this one is.
+    fn mode_fits(&self) -> bool {
+        matches!((self.offset, self.map_mode & 0x0F), (0x7FC0, 0x0 | 0x2 | 0x3) | (0xFFC0, 0x1 | 0xA) | (0x40FFC0, 0x5))
+    }
+    /// Whether this could be a header at all, however well it scores: it
+    /// needs a reset vector into ROM and either a matching checksum
+    /// complement or a map mode that fits.
+    fn plausible(&self) -> bool {
+        self.reset_vector >= 0x8000 && (self.complement_fits() || self.mode_fits())
+    }
+    fn evaluate(&self, cart: &[u8]) -> i32 {
+        let mut score = 0;
+        if self.complement_fits() {
+            score += 4;
+            if self.checksum != 0 && self.checksum != 0xFFFF { score += 2; }
+        }
+        if self.mode_fits() { score += 3; }
+        if self.map_mode & 0xE0 == 0x20 { score += 1; } else { score -= 2; }
+        if (0x40000..=0x800000).contains(&self.rom_size) {
+            score += 1;
+            if self.rom_size >= cart.len() / 2 && self.rom_size <= cart.len() * 2 { score += 1; }
+        }
+        if self.sram_size <= 0x80000 { score += 1; }
+        let title = &cart[self.offset..self.offset + 0x15];
+        if title.iter().all(|c| matches!(c, 0x20..=0x7E | 0xA1..=0xDF)) { score += 1; }
+        if self.reset_vector >= 0x8000 {
+            score += 2;
+            // what the first instruction of a reset routine usually looks like
+            score += match cart.get(self.reset_offset()) {
+                // sei, clc, sep, rep, jml, jmp, stz
+                Some(0x78 | 0x18 | 0xE2 | 0xC2 | 0x5C | 0x4C | 0x9C) => 2,
+                // brk, cop, wdm, stp, sbc long
+                Some(0x00 | 0x02 | 0x42 | 0xDB | 0xFF) | None => -4,
+                _ => 0,
+            };
+        } else {
+            score -= 4;
+        }
+        score
+    }
+}
+
+/// Finds the most plausible internal header in a headerless image, if any
+/// of the candidates looks like one.
+pub fn detect(cart: &[u8]) -> Option<CartridgeHeader> {
+    CANDIDATES.iter()
+        .filter_map(|&off| CartridgeHeader::parse(cart, off))
+        .filter(|h| h.plausible())
+        .map(|mut h| { h.score = h.evaluate(cart); h })
+        // on a tie, prefer the lower candidate
+        .max_by(|a, b| a.score.cmp(&b.score).then(b.offset.cmp(&a.offset)))
+}
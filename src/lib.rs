@@ -1,16 +1,21 @@
 use std::collections::HashMap;
 use egui::*;
-use egui::text::CursorRange;
+use egui::text::{CursorRange, LayoutJob};
+use egui_dock::{DockArea, DockState, NodeIndex, TabViewer};
 use egui_extras::{Size, StripBuilder};
 
 use egui_phosphor::regular as icons;
 
 use driver::GlobalState;
-use dis::LineKind;
+use dis::{DataKind, EdgeKind, ForcedState, LineKind, Rule, Severity, XrefKind};
+use patch::PatchFormat;
 
 pub mod driver;
+pub mod graph;
 pub mod cpu;
 pub mod dis;
+pub mod header;
+pub mod patch;
 pub mod rom;
 
 pub struct App {
@@ -19,12 +24,81 @@ pub struct App {
     // this is separate to allow detecting when the bank value actually changed
     bank_value: u8,
     currently_edited_text: Option<String>,
+    show_rom_info: bool,
+    goto: GotoDialog,
+    export: ExportDialog,
+    // address to bring into view on the next frame
+    scroll_to: Option<u32>,
+    // line the user last clicked on
+    caret: Option<(u32, LineKind)>,
+    // result of the last file operation, shown in the toolbar
+    status: Option<String>,
+    dock: DockState<Tab>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tab {
+    Listing,
+    Graph,
+    References,
+    Problems,
+}
+
+#[derive(Default)]
+struct GotoDialog {
+    open: bool,
+    kind: GotoKind,
+    text: String,
+    error: Option<String>,
+}
+
+struct ExportDialog {
+    open: bool,
+    format: PatchFormat,
+    fname: String,
+    replace: bool,
+    error: Option<String>,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+enum GotoKind {
+    #[default]
+    Snes,
+    PcOffset,
 }
 
 impl App {
     pub fn new(state: GlobalState) -> Self {
-        Self { bank_value: state.bank, state, currently_edited_text: None, }
+        let mut dock = DockState::new(vec![Tab::Listing, Tab::Graph]);
+        dock.main_surface_mut().split_right(NodeIndex::root(), 0.75, vec![Tab::References, Tab::Problems]);
+        Self {
+            bank_value: state.bank,
+            currently_edited_text: None,
+            show_rom_info: false,
+            goto: Default::default(),
+            export: ExportDialog { open: false, format: PatchFormat::Ips, fname: String::new(), replace: false, error: None },
+            scroll_to: None,
+            caret: None,
+            status: state.patch_error.clone(),
+            state,
+            dock,
+        }
     }
+    /// Switches to the bank containing `addr` and scrolls the listing to it.
+    pub fn navigate(&mut self, addr: u32) {
+        self.bank_value = (addr >> 16) as u8;
+        if self.state.bank != self.bank_value {
+            self.state.bank = self.bank_value;
+            self.state.update_lines();
+        }
+        self.scroll_to = Some(addr);
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let text = text.strip_prefix('$').or(text.strip_prefix("0x")).unwrap_or(text);
+    u32::from_str_radix(&text.replace(':', ""), 16).ok()
 }
 
 impl eframe::App for App {
@@ -40,10 +114,60 @@ impl eframe::App for App {
             });
         });
 
-        CentralPanel::default().show(ctx, |ui| {
-            ui.add_space(ui.spacing().item_spacing.y);
-            self.editor(ui);
+        // the tabs need the whole app, dock state included
+        let mut dock = std::mem::replace(&mut self.dock, DockState::new(vec![]));
+        DockArea::new(&mut dock).style(egui_dock::Style::from_egui(&ctx.style())).show(ctx, self);
+        self.dock = dock;
+
+        let mut show_rom_info = self.show_rom_info;
+        Window::new("ROM info").open(&mut show_rom_info).resizable(false).show(ctx, |ui| {
+            self.rom_info(ui);
+        });
+        self.show_rom_info = show_rom_info;
+
+        if ctx.input_mut(|i| i.consume_key(Modifiers::COMMAND, Key::G)) {
+            self.goto.open = true;
+        }
+        let mut goto_open = self.goto.open;
+        Window::new("Go to address").open(&mut goto_open).collapsible(false).resizable(false).show(ctx, |ui| {
+            self.goto_dialog(ui);
         });
+        self.goto.open &= goto_open;
+
+        let mut export_open = self.export.open;
+        Window::new("Export patch").open(&mut export_open).collapsible(false).resizable(false).show(ctx, |ui| {
+            self.export_dialog(ui);
+        });
+        self.export.open &= export_open;
+    }
+}
+
+impl TabViewer for App {
+    type Tab = Tab;
+
+    fn title(&mut self, tab: &mut Tab) -> WidgetText {
+        match tab {
+            Tab::Listing => "Listing",
+            Tab::Graph => "Graph",
+            Tab::References => "References",
+            Tab::Problems => "Problems",
+        }.into()
+    }
+
+    fn ui(&mut self, ui: &mut Ui, tab: &mut Tab) {
+        match tab {
+            Tab::Listing => {
+                ui.add_space(ui.spacing().item_spacing.y);
+                self.editor(ui);
+            }
+            Tab::Graph => self.graph_view(ui),
+            Tab::References => self.xref_panel(ui),
+            Tab::Problems => self.problems_panel(ui),
+        }
+    }
+
+    fn closeable(&mut self, tab: &mut Tab) -> bool {
+        *tab != Tab::Listing
     }
 }
 
@@ -59,6 +183,26 @@ impl App {
                 ui.close_menu();
                 self.state.save();
             }
+            if ui.button("ROM info").clicked() {
+                ui.close_menu();
+                self.show_rom_info = true;
+            }
+
+            ui.separator();
+
+            for (text, format) in [("Export IPS patch...", PatchFormat::Ips), ("Export BPS patch...", PatchFormat::Bps)] {
+                if ui.button(text).clicked() {
+                    ui.close_menu();
+                    self.export = ExportDialog {
+                        open: true,
+                        format,
+                        fname: self.state.default_patch_name(format),
+                        replace: false,
+                        error: None,
+                    };
+                }
+            }
+            ui.checkbox(&mut self.state.fix_checksum, "Fix checksum on export");
 
             ui.separator();
 
@@ -104,7 +248,280 @@ impl App {
             }
 
             if ui.button("Go to address...").clicked() {
-                // open popup with text box to provide ROM address and navigate to the address in code upon pressing enter
+                ui.close_menu();
+                self.goto.open = true;
+            }
+        });
+
+        ui.menu_button("View", |ui| {
+            for (text, tab) in [("Graph", Tab::Graph), ("References", Tab::References), ("Problems", Tab::Problems)] {
+                if ui.button(text).clicked() {
+                    ui.close_menu();
+                    match self.dock.find_tab(&tab) {
+                        Some(c) => self.dock.set_active_tab(c),
+                        None => self.dock.push_to_focused_leaf(tab),
+                    }
+                }
+            }
+        });
+    }
+
+    fn goto_dialog(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.goto.kind, GotoKind::Snes, "SNES address");
+            ui.radio_value(&mut self.goto.kind, GotoKind::PcOffset, "PC offset");
+        });
+        let response = ui.add(TextEdit::singleline(&mut self.goto.text).font(TextStyle::Monospace));
+        if !response.has_focus() && !response.lost_focus() {
+            response.request_focus();
+        }
+        if let Some(error) = &self.goto.error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+        if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+            let target = match (self.goto.kind, parse_hex(&self.goto.text)) {
+                (_, None) => Err(format!("\"{}\" is not a hex number", self.goto.text.trim())),
+                (GotoKind::Snes, Some(addr)) => Ok(addr & 0xFFFFFF),
+                (GotoKind::PcOffset, Some(off)) => self.state.resolve_offset(off as usize)
+                    .ok_or_else(|| format!("PC offset ${:06X} is not mapped", off)),
+            };
+            match target {
+                Ok(addr) => {
+                    self.goto.error = None;
+                    self.goto.open = false;
+                    self.navigate(addr);
+                }
+                Err(e) => self.goto.error = Some(e),
+            }
+        }
+    }
+
+    fn export_dialog(&mut self, ui: &mut Ui) {
+        ui.label(format!("{} patch from the original ROM to the current one", self.export.format.extension().to_uppercase()));
+        let response = ui.add(TextEdit::singleline(&mut self.export.fname).font(TextStyle::Monospace).desired_width(320.0));
+        ui.checkbox(&mut self.export.replace, "Replace the file if it exists");
+        if let Some(error) = &self.export.error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+        let enter = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+        if ui.button("Export").clicked() || enter {
+            match self.state.export_patch(self.export.format, &self.export.fname, self.export.replace) {
+                Ok(()) => {
+                    self.status = Some(format!("Wrote {}", self.export.fname));
+                    self.export.open = false;
+                }
+                Err(e) => self.export.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    /// The address the xref panel shows references to: a label, or
+    /// whatever the instruction under the caret refers to.
+    fn xref_subject(&self) -> Option<u32> {
+        let (pc, kind) = self.caret?;
+        let Some(entry) = self.state.dis.entries.get(&pc).filter(|_| matches!(kind, LineKind::Code)) else {
+            return Some(pc);
+        };
+        let instr = entry.instr;
+        let target = instr.jump_target(pc)
+            .or_else(|| instr.label_target(pc, &entry.state).filter(|_| instr.mode != cpu::Mode::Imm));
+        Some(target.unwrap_or(pc))
+    }
+
+    fn xref_panel(&mut self, ui: &mut Ui) {
+        let Some(subject) = self.xref_subject() else {
+            ui.label("Click a line to see what refers to it");
+            return;
+        };
+        let dis = &self.state.dis;
+        let name = if dis.entries.contains_key(&subject) || dis.labels.contains(&subject) {
+            dis.get_label(subject)
+        } else {
+            dis.get_data_label(subject)
+        };
+        ui.heading(format!("References to {}", name));
+        ui.separator();
+        let xrefs = dis.xrefs_to(subject);
+        if xrefs.is_empty() {
+            ui.label("None found");
+            return;
+        }
+        let groups = [
+            ("Calls", &[XrefKind::Call, XrefKind::JumpTable][..]),
+            ("Jumps", &[XrefKind::Jump, XrefKind::Branch]),
+            ("Reads", &[XrefKind::Read]),
+            ("Writes", &[XrefKind::Write, XrefKind::ReadModifyWrite]),
+            ("Pointers", &[XrefKind::Pointer]),
+        ];
+        let mut clicked = None;
+        ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
+            for (title, kinds) in groups {
+                let mut refs = xrefs.iter().filter(|c| kinds.contains(&c.kind)).collect::<Vec<_>>();
+                if refs.is_empty() { continue; }
+                refs.sort_by_key(|c| c.from);
+                CollapsingHeader::new(format!("{} ({})", title, refs.len())).default_open(true).show(ui, |ui| {
+                    for xref in refs {
+                        let sub = dis.entries.get(&xref.from).map(|c| dis.get_label(c.subroutine)).unwrap_or_default();
+                        let text = RichText::new(format!("{:06X} {:<14} {}", xref.from, sub, xref.instr)).monospace();
+                        if ui.selectable_label(false, text).clicked() {
+                            clicked = Some(xref.from);
+                        }
+                    }
+                });
+            }
+        });
+        if let Some(from) = clicked {
+            self.set_caret(from, LineKind::Code);
+            self.navigate(from);
+        }
+    }
+
+    fn problems_panel(&mut self, ui: &mut Ui) {
+        let dis = &self.state.dis;
+        if dis.diagnostics.is_empty() {
+            ui.label("No problems found");
+            return;
+        }
+        let mut problems = dis.diagnostics.iter().collect::<Vec<_>>();
+        problems.sort_by_key(|c| (std::cmp::Reverse(c.severity), c.pc));
+        let mut clicked = None;
+        ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
+            for problem in problems {
+                ui.horizontal(|ui| {
+                    let (icon, color) = match problem.severity {
+                        Severity::Error => (icons::X_CIRCLE, ui.visuals().error_fg_color),
+                        Severity::Warning => (icons::WARNING, ui.visuals().warn_fg_color),
+                        Severity::Info => (icons::INFO, ui.visuals().weak_text_color()),
+                    };
+                    ui.colored_label(color, icon);
+                    let sub = problem.subroutine.map(|c| dis.get_label(c)).unwrap_or_default();
+                    let text = RichText::new(format!("{:06X} {:<14} {}: {}", problem.pc, sub, problem.category, problem.message)).monospace();
+                    if ui.selectable_label(false, text).clicked() {
+                        clicked = Some(problem.pc);
+                    }
+                });
+            }
+        });
+        if let Some(pc) = clicked {
+            self.set_caret(pc, LineKind::Code);
+            self.navigate(pc);
+        }
+    }
+
+    /// Control flow graph of the subroutine the caret is in.
+    fn graph_view(&mut self, ui: &mut Ui) {
+        let dis = &self.state.dis;
+        let Some(sub) = self.caret.and_then(|(pc, _)| dis.block_at(pc)).map(|c| c.subroutine) else {
+            ui.label("Click an instruction to see the control flow of its subroutine");
+            return;
+        };
+        let font_id = TextStyle::Monospace.resolve(ui.style());
+        let row_height = ui.text_style_height(&TextStyle::Monospace);
+        let char_width = ui.fonts(|fonts| fonts.glyph_width(&font_id, 'x'));
+        let padding = vec2(6.0, 4.0);
+
+        let mut text: HashMap<u32, Vec<(u32, LineKind, String)>> = HashMap::new();
+        for &start in dis.subroutines[&sub].blocks.iter() {
+            let lines = text.entry(start).or_default();
+            if dis.labels.contains(&start) || dis.label_names.contains_key(&start) {
+                lines.push((start, LineKind::Label, format!("{}:", dis.get_label(start))));
+            }
+            let last = dis.blocks[&start].last;
+            let mut pc = start;
+            loop {
+                let entry = &dis.entries[&pc];
+                lines.push((pc, LineKind::Code, format!("    {}", dis.instr_text(pc, entry))));
+                if pc == last { break; }
+                pc += entry.instr.size as u32 + 1;
+            }
+        }
+        let layout = graph::layout(dis, sub, |start| {
+            let lines = &text[&start];
+            let chars = lines.iter().map(|c| c.2.len()).max().unwrap_or(0);
+            vec2(chars as f32 * char_width, lines.len() as f32 * row_height) + padding * 2.0
+        });
+
+        let mut clicked = None;
+        ScrollArea::both().id_source(("graph", sub)).auto_shrink(false).show(ui, |ui| {
+            let (response, painter) = ui.allocate_painter(layout.size, Sense::hover());
+            let origin = response.rect.min.to_vec2();
+            for edge in layout.edges.iter() {
+                let color = match edge.kind {
+                    EdgeKind::BranchTaken => Color32::from_rgb(0x40, 0xC0, 0x40),
+                    EdgeKind::Fallthrough => Color32::from_rgb(0xE0, 0x50, 0x50),
+                    _ => Color32::GRAY,
+                };
+                let points = edge.points.iter().map(|&c| c + origin).collect::<Vec<_>>();
+                let tip = *points.last().unwrap();
+                painter.add(Shape::line(points, Stroke::new(1.5, color)));
+                painter.add(Shape::convex_polygon(vec![tip, tip + vec2(4.0, -7.0), tip + vec2(-4.0, -7.0)], color, Stroke::NONE));
+            }
+            for (start, rect) in layout.blocks.iter() {
+                let rect = rect.translate(origin);
+                painter.rect(rect, 2.0, ui.visuals().extreme_bg_color, ui.visuals().widgets.noninteractive.bg_stroke);
+                for (i, (pc, kind, line)) in text[start].iter().enumerate() {
+                    let line_rect = Rect::from_min_size(
+                        rect.min + padding + vec2(0.0, i as f32 * row_height),
+                        vec2(rect.width() - padding.x * 2.0, row_height),
+                    );
+                    let response = ui.interact(line_rect, ui.id().with(("graph-line", pc, i)), Sense::click());
+                    if response.clicked() {
+                        clicked = Some((*pc, *kind));
+                    }
+                    if matches!(kind, LineKind::Code) && self.caret.map_or(false, |(c, _)| c == *pc) {
+                        painter.rect_filled(line_rect, 0.0, ui.visuals().selection.bg_fill);
+                    }
+                    painter.text(line_rect.left_center(), Align2::LEFT_CENTER, line, font_id.clone(), Color32::WHITE);
+                }
+            }
+        });
+        if let Some((pc, kind)) = clicked {
+            self.set_caret(pc, kind);
+            self.navigate(pc);
+        }
+    }
+
+    fn rom_info(&mut self, ui: &mut Ui) {
+        let mapper = self.state.dis.rom.mapper();
+        Grid::new("rom-info-grid").num_columns(2).striped(true).show(ui, |ui| {
+            let mut row = |name: &str, value: String| {
+                ui.label(name);
+                ui.monospace(value);
+                ui.end_row();
+            };
+            // what was done to the dump matters most when no header turns up
+            for step in self.state.normalization.iter() {
+                row("Image", step.to_string());
+            }
+            let Some(header) = &self.state.header else {
+                row("Header", format!("None found, assuming {:?}", mapper));
+                return;
+            };
+            row("Title", header.title.clone());
+            row("Header offset", format!("${:06X}", header.offset));
+            row("Mapper", format!("{:?}{}", mapper, if header.fast_rom { " (FastROM)" } else { "" }));
+            row("Map mode", format!("${:02X}", header.map_mode));
+            let chipset = &header.chipset;
+            row("Chipset", format!("{}{}{}",
+                chipset.coprocessor.map(|c| format!("{:?} ", c)).unwrap_or_default(),
+                if chipset.ram { "RAM " } else { "" },
+                if chipset.battery { "Battery" } else { "" },
+            ));
+            row("ROM size", format!("{} KiB", header.rom_size / 1024));
+            row("SRAM size", format!("{} KiB", header.sram_size / 1024));
+            row("Region", format!("{:?}", header.region));
+            row("Developer", match &header.maker_code {
+                Some(c) => format!("${:02X} ({})", header.developer_id, c),
+                None => format!("${:02X}", header.developer_id),
+            });
+            row("Version", format!("1.{}", header.version));
+            row("Checksum", format!("${:04X} / ${:04X}", header.checksum, header.complement));
+            if let Some(checksum) = self.state.checksum {
+                row("Computed", if checksum.is_valid() {
+                    format!("${:04X} (valid)", checksum.computed)
+                } else {
+                    format!("${:04X} (mismatch)", checksum.computed)
+                });
             }
         });
     }
@@ -127,17 +544,80 @@ impl App {
 
         ui.separator();
 
+        if let Some(status) = &self.status {
+            ui.label(status);
+        }
+
         ui.add_space(ui.available_width());
     }
 
+    /// Moves the caret to a line, selecting just that line.
+    fn set_caret(&mut self, pc: u32, kind: LineKind) {
+        self.caret = Some((pc, kind));
+        self.state.selection = Some([pc, pc]);
+    }
+
+    /// Context menu for the selected lines, returns the rules to replace
+    /// what's there with.
+    fn selection_menu(&self, ui: &mut Ui, range: std::ops::Range<u32>) -> Option<Vec<Rule>> {
+        let mut rules = None;
+        ui.label(RichText::new(format!("{:06X}-{:06X}", range.start, range.end - 1)).monospace().weak());
+        ui.separator();
+        if ui.button("Disassemble").clicked() {
+            // keep what analysis already knew if it decoded something here
+            let state = self.state.dis.entries.get(&range.start).map_or(Default::default(), |c| ForcedState {
+                m: Some(c.state.m), x: Some(c.state.x), e: c.state.e, db: c.state.db, d: c.state.d,
+            });
+            rules = Some(vec![Rule::Entry { pc: range.start, state }]);
+        }
+        ui.separator();
+        let kinds = [
+            ("Bytes", DataKind::Bytes), ("Words", DataKind::Words), ("Word ptrs", DataKind::WordPtrs),
+            ("Longs", DataKind::Longs), ("Long ptrs", DataKind::LongPtrs), ("String", DataKind::Ascii),
+        ];
+        for (name, kind) in kinds {
+            if ui.button(name).clicked() {
+                rules = Some(vec![Rule::Data { start: range.start, end: range.end, kind, per_line: None }]);
+            }
+        }
+        ui.separator();
+        // a call to a dispatcher, followed by the table
+        let call = self.state.dis.entries.get(&range.start).map_or(false, |c| c.instr.mnemonic == cpu::Mnemonic::JSL);
+        for (name, long) in [("Jump table", false), ("Long jump table", true)] {
+            let (width, kind) = if long { (3, DataKind::LongPtrs) } else { (2, DataKind::WordPtrs) };
+            let size = (range.end - range.start).saturating_sub(4) / width;
+            if ui.add_enabled(call && size > 0, Button::new(name)).clicked() {
+                let table = range.start + 4;
+                rules = Some(vec![
+                    Rule::JumpTable { pc: range.start, size, long },
+                    Rule::Data { start: table, end: table + size * width, kind, per_line: None },
+                ]);
+            }
+        }
+        if rules.is_some() {
+            ui.close_menu();
+        }
+        rules
+    }
+
     fn editor(&mut self, ui: &mut Ui) {
+        // rules picked from the context menu, applied once the lines are drawn
+        let mut retype = None;
         Frame::canvas(ui.style()).show(ui, |ui| {
             let text_style = TextStyle::Monospace;
             let row_height = ui.text_style_height(&text_style);
             let num_rows = self.state.lines.len();
             let font_id = text_style.resolve(ui.style());
             let char_width = ui.fonts(|fonts| fonts.glyph_width(&font_id, 'x'));
-            ScrollArea::vertical().auto_shrink(false).show_rows(ui, row_height, num_rows, |ui, row_range| {
+            let mut scroll_area = ScrollArea::vertical().auto_shrink(false);
+            if let Some(target) = self.scroll_to.take() {
+                // first line at the target, or the line containing it
+                let lines = &self.state.lines;
+                let mut idx = lines.partition_point(|l| l.pc < target);
+                if idx > 0 && lines.get(idx).map_or(true, |l| l.pc != target) { idx -= 1; }
+                scroll_area = scroll_area.vertical_scroll_offset(idx as f32 * (row_height + ui.spacing().item_spacing.y));
+            }
+            scroll_area.show_rows(ui, row_height, num_rows, |ui, row_range| {
                 // contents of the editor
                 let mut prev_line_pc = 0;
                 let mut line_idx_at_this_pc = 0usize;
@@ -156,20 +636,36 @@ impl App {
                                     line_idx_at_this_pc = 0;
                                 }
 
-                                strip.cell(|ui| { ui.monospace(format!("{:06X}", line_pc)); });
+                                strip.cell(|ui| {
+                                    let text = RichText::new(format!("{:06X}", line_pc)).monospace();
+                                    if self.state.is_patched(line_pc) {
+                                        ui.label(text.color(Color32::from_rgb(0xFF, 0xA0, 0x40)));
+                                    } else {
+                                        ui.label(text);
+                                    }
+                                });
                                 strip.cell(|ui| {
                                     if matches!(line_kind, LineKind::Label) {
                                         let default = self.state.dis.get_label(line_pc);
+                                        let hint = self.state.dis.xref_hint(line_pc);
                                         let label = self.state.dis.label_names.entry(line_pc).or_insert(default);
                                         let mut output = TextEdit::singleline(label)
                                             .frame(false)
                                             .font(TextStyle::Monospace)
                                             .desired_width(f32::INFINITY)
                                             .margin(Vec2::ZERO)
-                                            .layouter(&mut |ui, string, wrap_width| {
-                                                WidgetText::from(string.to_string() + ":").into_galley(ui, None, ui.available_width(), TextStyle::Monospace)
+                                            .layouter(&mut |ui, string, _wrap_width| {
+                                                let font = TextStyle::Monospace.resolve(ui.style());
+                                                let mut job = LayoutJob::default();
+                                                job.append(&(string.to_string() + ":"), 0.0, TextFormat::simple(font.clone(), ui.visuals().text_color()));
+                                                job.append(&hint, 0.0, TextFormat::simple(font, ui.visuals().weak_text_color()));
+                                                ui.fonts(|f| f.layout_job(job))
                                             })
                                             .show(ui);
+                                        if output.response.gained_focus() {
+                                            self.caret = Some((line_pc, line_kind));
+                                            self.state.selection = Some([line_pc, line_pc]);
+                                        }
                                         if output.response.changed() {
                                             let new_len = label.len();
                                             label.retain(|c| c.is_ascii_alphanumeric() || "_.".contains(c));
@@ -184,7 +680,27 @@ impl App {
                                             self.state.update_lines();
                                         };
                                     } else {
-                                        ui.monospace(RichText::new(self.state.lines[i].text.trim_end()).color(Color32::WHITE));
+                                        let text = RichText::new(self.state.lines[i].text.trim_end()).monospace().color(Color32::WHITE);
+                                        let background = ui.painter().add(Shape::Noop);
+                                        let response = ui.add(Label::new(text).sense(Sense::click()));
+                                        let selected = |state: &GlobalState| state.selection
+                                            .map_or(false, |[a, b]| (a.min(b)..=a.max(b)).contains(&line_pc));
+                                        if response.clicked() && ui.input(|i| i.modifiers.shift) && self.state.selection.is_some() {
+                                            self.caret = Some((line_pc, line_kind));
+                                            self.state.selection.as_mut().unwrap()[1] = line_pc;
+                                        } else if response.clicked() || (response.secondary_clicked() && !selected(&self.state)) {
+                                            self.set_caret(line_pc, line_kind);
+                                        }
+                                        if selected(&self.state) {
+                                            ui.painter().set(background, Shape::rect_filled(response.rect, 0.0, ui.visuals().selection.bg_fill));
+                                        }
+                                        response.context_menu(|ui| {
+                                            if let Some(range) = self.state.selected_range() {
+                                                if let Some(rules) = self.selection_menu(ui, range.clone()) {
+                                                    retype = Some((range, rules));
+                                                }
+                                            }
+                                        });
                                     }
                                 });
                                 strip.cell(|ui| {
@@ -194,8 +710,13 @@ impl App {
                                         .flatten()
                                         .unwrap_or("".to_owned());
 
+                                    let note = match line_kind {
+                                        LineKind::Code => self.state.dis.entries.get(&line_pc).and_then(|c| self.state.dis.operand_note(c)),
+                                        _ => None,
+                                    };
                                     ui.monospace("; ");
                                     if TextEdit::singleline(&mut comment)
+                                        .hint_text(RichText::new(note.unwrap_or_default()).monospace())
                                         .frame(false)
                                         .font(TextStyle::Monospace)
                                         .desired_width(f32::INFINITY)
@@ -232,5 +753,8 @@ impl App {
                 }
             });
         });
+        if let Some((range, rules)) = retype {
+            self.state.retype(range, rules);
+        }
     }
 }
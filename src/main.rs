@@ -12,11 +12,22 @@ use snes_iris::driver::GlobalState;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::EventLoop;
 use snes_iris::dis::{Disassembler, Rule};
+use snes_iris::patch::{Patch, PatchFormat};
 use snes_iris::rom::{Mapper, Rom};
 
 fn main() {
     // imgui_app();
 
+    let args: Vec<String> = std::env::args().collect();
+    match &args[1..] {
+        [cmd, original, modified, out] if cmd == "make-patch" => return make_patch(original, modified, out),
+        [cmd, ..] if cmd == "make-patch" => {
+            eprintln!("usage: snes-iris make-patch <original> <modified> <out.ips|out.bps>");
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+
     eframe::run_native(
         "snes-iris",
         NativeOptions::default(),
@@ -30,6 +41,30 @@ fn main() {
     ).ok();
 }
 
+fn make_patch(original: &str, modified: &str, out: &str) {
+    fn fail(message: String) -> ! {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+    let format = PatchFormat::from_extension(out)
+        .unwrap_or_else(|| fail(format!("{}: expected a .ips or .bps file name", out)));
+    // writing the patch over one of the files it's made from loses that file
+    if let Ok(target) = std::fs::canonicalize(out) {
+        for c in [original, modified] {
+            if std::fs::canonicalize(c).map_or(false, |c| c == target) {
+                fail(format!("{}: won't write the patch over one of its inputs", out));
+            }
+        }
+    }
+    let source = std::fs::read(original).unwrap_or_else(|e| fail(format!("can't read {}: {}", original, e)));
+    let target = std::fs::read(modified).unwrap_or_else(|e| fail(format!("can't read {}: {}", modified, e)));
+    let patch = Patch::create(format, &source, &target)
+        .unwrap_or_else(|e| fail(format!("can't create {}: {}", out, e)));
+    if let Err(e) = std::fs::write(out, patch.to_bytes()) {
+        fail(format!("can't write {}: {}", out, e));
+    }
+}
+
 fn main2() {
     let rom = Rom::new(std::fs::read("smw.sfc").unwrap()[0x200..].to_vec(), Mapper::LoRom);
     let mut dis = Disassembler::new(rom);
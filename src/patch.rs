@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Clone, Debug)]
+pub enum Patch {
+    Ips(IpsPatch),
+    Bps(BpsPatch),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IpsPatch {
+    pub records: Vec<IpsRecord>,
+    /// Size to truncate the output to, from the optional trailer after EOF
+    pub truncate: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IpsRecord {
+    pub offset: usize,
+    pub data: IpsData,
+}
+
+#[derive(Clone, Debug)]
+pub enum IpsData {
+    Literal(Vec<u8>),
+    Rle { len: usize, byte: u8 },
+}
+
+#[derive(Clone, Debug)]
+pub struct BpsPatch {
+    pub source_size: usize,
+    pub target_size: usize,
+    pub metadata: String,
+    pub actions: Vec<BpsAction>,
+    pub source_crc: u32,
+    pub target_crc: u32,
+    pub patch_crc: u32,
+}
+
+/// A BPS action. Copy offsets are absolute here; the relative encoding
+/// only matters on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BpsAction {
+    SourceRead { len: usize },
+    TargetRead { data: Vec<u8> },
+    SourceCopy { len: usize, offset: usize },
+    TargetCopy { len: usize, offset: usize },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    /// IPS can't address anything at or past 16MB
+    TooLarge { offset: usize },
+    /// A BPS copy reads outside of the source or target
+    BadCopy { offset: usize },
+    SourceSize { expected: usize, actual: usize },
+    SourceCrc { expected: u32, actual: u32 },
+    TargetCrc { expected: u32, actual: u32 },
+    PatchCrc { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use PatchError::*;
+        match self {
+            UnknownFormat => write!(f, "not an IPS or BPS patch"),
+            Truncated => write!(f, "patch is truncated"),
+            TooLarge { offset } => write!(f, "change at ${:06X} is out of IPS range", offset),
+            BadCopy { offset } => write!(f, "copy from out of range offset ${:06X}", offset),
+            SourceSize { expected, actual } => write!(f, "source is {} bytes, patch expects {}", actual, expected),
+            SourceCrc { expected, actual } => write!(f, "source CRC32 is {:08X}, patch expects {:08X}", actual, expected),
+            TargetCrc { expected, actual } => write!(f, "output CRC32 is {:08X}, patch expects {:08X}", actual, expected),
+            PatchCrc { expected, actual } => write!(f, "patch CRC32 is {:08X}, footer says {:08X}", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl PatchFormat {
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let ext = std::path::Path::new(path).extension()?.to_str()?;
+        match ext.to_ascii_lowercase().as_str() {
+            "ips" => Some(PatchFormat::Ips),
+            "bps" => Some(PatchFormat::Bps),
+            _ => None,
+        }
+    }
+    pub fn extension(self) -> &'static str {
+        match self {
+            PatchFormat::Ips => "ips",
+            PatchFormat::Bps => "bps",
+        }
+    }
+}
+
+impl Patch {
+    /// Builds a patch that turns `source` into `target`.
+    pub fn create(format: PatchFormat, source: &[u8], target: &[u8]) -> Result<Self, PatchError> {
+        Ok(match format {
+            PatchFormat::Ips => Patch::Ips(IpsPatch::create(source, target)?),
+            PatchFormat::Bps => Patch::Bps(BpsPatch::create(source, target)),
+        })
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Patch::Ips(c) => c.to_bytes(),
+            Patch::Bps(c) => c.to_bytes(),
+        }
+    }
+    pub fn parse(bytes: &[u8]) -> Result<Self, PatchError> {
+        if bytes.starts_with(b"PATCH") {
+            IpsPatch::parse(bytes).map(Patch::Ips)
+        } else if bytes.starts_with(b"BPS1") {
+            BpsPatch::parse(bytes).map(Patch::Bps)
+        } else {
+            Err(PatchError::UnknownFormat)
+        }
+    }
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+        match self {
+            Patch::Ips(c) => Ok(c.apply(source)),
+            Patch::Bps(c) => c.apply(source),
+        }
+    }
+    /// Offsets in the output that the patch writes, sorted and merged.
+    pub fn touched(&self) -> Vec<Range<usize>> {
+        let ranges = match self {
+            Patch::Ips(c) => c.records.iter().map(|r| r.offset..r.offset + r.data.size()).collect(),
+            Patch::Bps(c) => c.touched(),
+        };
+        merge_ranges(ranges)
+    }
+}
+
+impl IpsData {
+    pub fn size(&self) -> usize {
+        match self {
+            IpsData::Literal(c) => c.len(),
+            IpsData::Rle { len, .. } => *len,
+        }
+    }
+}
+
+impl IpsPatch {
+    /// A record at this offset would read as the "EOF" marker.
+    const EOF_OFFSET: usize = 0x454F46;
+
+    pub fn create(source: &[u8], target: &[u8]) -> Result<Self, PatchError> {
+        let differs = |i: usize| source.get(i) != target.get(i);
+        let mut records = vec![];
+        let mut i = 0;
+        while i < target.len() {
+            if !differs(i) { i += 1; continue; }
+            // a new record costs 5 bytes, so short unchanged gaps are cheaper
+            // to include in the current one
+            let start = i;
+            let mut end = i;
+            let mut same = 0;
+            while end < target.len() && end - start < 0xFFFF {
+                if differs(end) { same = 0; } else if same == 5 { break; } else { same += 1; }
+                end += 1;
+            }
+            end -= same;
+            Self::push_records(&mut records, target, start..end)?;
+            i = end;
+        }
+        let truncate = (target.len() < source.len()).then_some(target.len());
+        Ok(Self { records, truncate })
+    }
+    /// Adds records for `range`, splitting out long runs as RLE.
+    fn push_records(records: &mut Vec<IpsRecord>, target: &[u8], range: Range<usize>) -> Result<(), PatchError> {
+        let mut push = |mut offset: usize, mut data: IpsData| {
+            if offset == Self::EOF_OFFSET {
+                // start one byte early, or shift an RLE run one byte late
+                match &mut data {
+                    IpsData::Literal(c) => { c.insert(0, target[offset - 1]); offset -= 1; }
+                    IpsData::Rle { len, byte } => {
+                        let lit = target[offset - 1..offset + 1].to_vec();
+                        records.push(IpsRecord { offset: offset - 1, data: IpsData::Literal(lit) });
+                        if *len == 1 { return; }
+                        *len -= 1;
+                        data = IpsData::Rle { len: *len, byte: *byte };
+                        offset += 1;
+                    }
+                }
+            }
+            records.push(IpsRecord { offset, data });
+        };
+        if range.end > 0x1000000 {
+            return Err(PatchError::TooLarge { offset: range.start.max(0x1000000) });
+        }
+        let mut lit_start = range.start;
+        let mut i = range.start;
+        while i < range.end {
+            let run = target[i..range.end].iter().take_while(|&&c| c == target[i]).count();
+            // an RLE record is 8 bytes, and splitting a literal costs another 5
+            if run >= 13 || (run >= 8 && i + run == range.end && i == lit_start) {
+                if lit_start < i {
+                    push(lit_start, IpsData::Literal(target[lit_start..i].to_vec()));
+                }
+                push(i, IpsData::Rle { len: run, byte: target[i] });
+                i += run;
+                lit_start = i;
+            } else {
+                i += run;
+            }
+        }
+        if lit_start < range.end {
+            push(lit_start, IpsData::Literal(target[lit_start..range.end].to_vec()));
+        }
+        Ok(())
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = b"PATCH".to_vec();
+        for rec in self.records.iter() {
+            out.extend_from_slice(&(rec.offset as u32).to_be_bytes()[1..]);
+            match &rec.data {
+                IpsData::Literal(c) => {
+                    out.extend_from_slice(&(c.len() as u16).to_be_bytes());
+                    out.extend_from_slice(c);
+                }
+                IpsData::Rle { len, byte } => {
+                    out.extend_from_slice(&[0, 0]);
+                    out.extend_from_slice(&(*len as u16).to_be_bytes());
+                    out.push(*byte);
+                }
+            }
+        }
+        out.extend_from_slice(b"EOF");
+        if let Some(len) = self.truncate {
+            out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+        }
+        out
+    }
+    pub fn parse(bytes: &[u8]) -> Result<Self, PatchError> {
+        let mut r = Reader { bytes, pos: 5 };
+        let mut records = vec![];
+        loop {
+            let head = r.take(3)?;
+            if head == b"EOF" { break; }
+            let offset = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+            let size = r.u16_be()? as usize;
+            let data = if size == 0 {
+                let len = r.u16_be()? as usize;
+                IpsData::Rle { len, byte: r.byte()? }
+            } else {
+                IpsData::Literal(r.take(size)?.to_vec())
+            };
+            records.push(IpsRecord { offset, data });
+        }
+        let truncate = match r.take(3) {
+            Ok(c) => Some((c[0] as usize) << 16 | (c[1] as usize) << 8 | c[2] as usize),
+            Err(_) => None,
+        };
+        Ok(Self { records, truncate })
+    }
+    pub fn apply(&self, source: &[u8]) -> Vec<u8> {
+        let mut out = source.to_vec();
+        for rec in self.records.iter() {
+            let end = rec.offset + rec.data.size();
+            if out.len() < end { out.resize(end, 0); }
+            match &rec.data {
+                IpsData::Literal(c) => out[rec.offset..end].copy_from_slice(c),
+                IpsData::Rle { byte, .. } => out[rec.offset..end].fill(*byte),
+            }
+        }
+        if let Some(len) = self.truncate {
+            out.truncate(len);
+        }
+        out
+    }
+}
+
+impl BpsPatch {
+    pub fn create(source: &[u8], target: &[u8]) -> Self {
+        // shortest match worth a copy action over literal bytes
+        const MIN: usize = 4;
+        let key = |data: &[u8], i: usize| data.get(i..i + MIN).map(|c| u32::from_le_bytes(c.try_into().unwrap()));
+        let common = |a: &[u8], b: &[u8]| a.iter().zip(b).take_while(|(a, b)| a == b).count();
+        let mut source_index = HashMap::new();
+        for i in 0..source.len().saturating_sub(MIN - 1) {
+            source_index.entry(key(source, i).unwrap()).or_insert(i);
+        }
+        let mut target_index = HashMap::new();
+        let mut actions = vec![];
+        let mut literal = vec![];
+        let mut pos = 0;
+        while pos < target.len() {
+            let read = common(source.get(pos..).unwrap_or(&[]), &target[pos..]);
+            let mut best = (read, BpsAction::SourceRead { len: read });
+            if let Some(k) = key(target, pos) {
+                if let Some(&off) = source_index.get(&k) {
+                    let len = common(&source[off..], &target[pos..]);
+                    if len > best.0 { best = (len, BpsAction::SourceCopy { len, offset: off }); }
+                }
+                if let Some(&off) = target_index.get(&k) {
+                    // the copy may overlap the bytes it produces
+                    let len = (0..target.len() - pos).take_while(|&i| target[off + i] == target[pos + i]).count();
+                    if len > best.0 { best = (len, BpsAction::TargetCopy { len, offset: off }); }
+                }
+            }
+            let len = if best.0 >= MIN || (best.0 > 0 && literal.is_empty() && matches!(best.1, BpsAction::SourceRead { .. })) {
+                if !literal.is_empty() {
+                    actions.push(BpsAction::TargetRead { data: std::mem::take(&mut literal) });
+                }
+                actions.push(best.1);
+                best.0
+            } else {
+                literal.push(target[pos]);
+                1
+            };
+            for i in pos..pos + len {
+                if let Some(k) = key(target, i) { target_index.insert(k, i); }
+            }
+            pos += len;
+        }
+        if !literal.is_empty() {
+            actions.push(BpsAction::TargetRead { data: literal });
+        }
+        let mut patch = Self {
+            source_size: source.len(),
+            target_size: target.len(),
+            metadata: String::new(),
+            actions,
+            source_crc: crc32(source),
+            target_crc: crc32(target),
+            patch_crc: 0,
+        };
+        let bytes = patch.to_bytes();
+        patch.patch_crc = u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        patch
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = b"BPS1".to_vec();
+        write_varint(&mut out, self.source_size);
+        write_varint(&mut out, self.target_size);
+        write_varint(&mut out, self.metadata.len());
+        out.extend_from_slice(self.metadata.as_bytes());
+        let (mut source_rel, mut target_rel) = (0isize, 0isize);
+        for action in self.actions.iter() {
+            let (kind, len) = match action {
+                BpsAction::SourceRead { len } => (0, *len),
+                BpsAction::TargetRead { data } => (1, data.len()),
+                BpsAction::SourceCopy { len, .. } => (2, *len),
+                BpsAction::TargetCopy { len, .. } => (3, *len),
+            };
+            write_varint(&mut out, (len - 1) << 2 | kind);
+            match action {
+                BpsAction::TargetRead { data } => out.extend_from_slice(data),
+                BpsAction::SourceCopy { len, offset } | BpsAction::TargetCopy { len, offset } => {
+                    let rel = if kind == 2 { &mut source_rel } else { &mut target_rel };
+                    let delta = *offset as isize - *rel;
+                    write_varint(&mut out, delta.unsigned_abs() << 1 | (delta < 0) as usize);
+                    *rel = (offset + len) as isize;
+                }
+                BpsAction::SourceRead { .. } => {}
+            }
+        }
+        out.extend_from_slice(&self.source_crc.to_le_bytes());
+        out.extend_from_slice(&self.target_crc.to_le_bytes());
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+    pub fn parse(bytes: &[u8]) -> Result<Self, PatchError> {
+        if bytes.len() < 4 + 12 { return Err(PatchError::Truncated); }
+        let body = &bytes[..bytes.len() - 12];
+        let footer = &bytes[bytes.len() - 12..];
+        let crc_at = |i: usize| u32::from_le_bytes(footer[i..i+4].try_into().unwrap());
+        let (source_crc, target_crc, patch_crc) = (crc_at(0), crc_at(4), crc_at(8));
+        let actual = crc32(&bytes[..bytes.len() - 4]);
+        if actual != patch_crc {
+            return Err(PatchError::PatchCrc { expected: patch_crc, actual });
+        }
+
+        let mut r = Reader { bytes: body, pos: 4 };
+        let source_size = r.varint()?;
+        let target_size = r.varint()?;
+        let metadata_size = r.varint()?;
+        let metadata = String::from_utf8_lossy(r.take(metadata_size)?).into_owned();
+        let mut actions = vec![];
+        let (mut out, mut source_rel, mut target_rel) = (0usize, 0isize, 0isize);
+        while r.pos < body.len() {
+            let data = r.varint()?;
+            let len = (data >> 2) + 1;
+            let action = match data & 3 {
+                0 => BpsAction::SourceRead { len },
+                1 => BpsAction::TargetRead { data: r.take(len)?.to_vec() },
+                kind => {
+                    let d = r.varint()?;
+                    let delta = if d & 1 != 0 { -((d >> 1) as isize) } else { (d >> 1) as isize };
+                    let rel = if kind == 2 { &mut source_rel } else { &mut target_rel };
+                    *rel += delta;
+                    let offset = usize::try_from(*rel).map_err(|_| PatchError::BadCopy { offset: out })?;
+                    *rel += len as isize;
+                    if kind == 2 {
+                        BpsAction::SourceCopy { len, offset }
+                    } else {
+                        BpsAction::TargetCopy { len, offset }
+                    }
+                }
+            };
+            out += len;
+            actions.push(action);
+        }
+        Ok(Self { source_size, target_size, metadata, actions, source_crc, target_crc, patch_crc })
+    }
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+        if source.len() != self.source_size {
+            return Err(PatchError::SourceSize { expected: self.source_size, actual: source.len() });
+        }
+        let actual = crc32(source);
+        if actual != self.source_crc {
+            return Err(PatchError::SourceCrc { expected: self.source_crc, actual });
+        }
+        let mut out = Vec::with_capacity(self.target_size);
+        for action in self.actions.iter() {
+            let pos = out.len();
+            match action {
+                BpsAction::SourceRead { len } => {
+                    out.extend_from_slice(source.get(pos..pos + len).ok_or(PatchError::BadCopy { offset: pos })?);
+                }
+                BpsAction::TargetRead { data } => out.extend_from_slice(data),
+                BpsAction::SourceCopy { len, offset } => {
+                    out.extend_from_slice(source.get(*offset..offset + len).ok_or(PatchError::BadCopy { offset: *offset })?);
+                }
+                BpsAction::TargetCopy { len, offset } => {
+                    // may overlap the bytes being written, so go one at a time
+                    for i in *offset..offset + len {
+                        let c = *out.get(i).ok_or(PatchError::BadCopy { offset: i })?;
+                        out.push(c);
+                    }
+                }
+            }
+        }
+        let actual = crc32(&out);
+        if actual != self.target_crc {
+            return Err(PatchError::TargetCrc { expected: self.target_crc, actual });
+        }
+        Ok(out)
+    }
+    fn touched(&self) -> Vec<Range<usize>> {
+        let mut ranges = vec![];
+        let mut pos = 0;
+        for action in self.actions.iter() {
+            let len = match action {
+                BpsAction::SourceRead { len } => *len,
+                // copying a byte onto itself doesn't change anything
+                BpsAction::SourceCopy { len, offset } if *offset == pos => *len,
+                BpsAction::TargetRead { data } => { ranges.push(pos..pos + data.len()); data.len() }
+                BpsAction::SourceCopy { len, .. } | BpsAction::TargetCopy { len, .. } => { ranges.push(pos..pos + len); *len }
+            };
+            pos += len;
+        }
+        ranges
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut data: usize) {
+    loop {
+        let x = (data & 0x7F) as u8;
+        data >>= 7;
+        if data == 0 {
+            out.push(0x80 | x);
+            break;
+        }
+        out.push(x);
+        data -= 1;
+    }
+}
+
+fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|c| c.start);
+    let mut out: Vec<Range<usize>> = vec![];
+    for r in ranges {
+        match out.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => out.push(r),
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let c = self.bytes.get(self.pos..self.pos + len).ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(c)
+    }
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16_be(&mut self) -> Result<u16, PatchError> {
+        let c = self.take(2)?;
+        Ok(u16::from_be_bytes([c[0], c[1]]))
+    }
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.byte()?;
+            data = data.checked_add((x & 0x7F) as usize * shift).ok_or(PatchError::Truncated)?;
+            if x & 0x80 != 0 { break; }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Truncated)?;
+            data = data.checked_add(shift).ok_or(PatchError::Truncated)?;
+        }
+        Ok(data)
+    }
+}
+
+static CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that don't repeat, so BPS has nothing to copy by accident
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| { x = x.wrapping_mul(1103515245).wrapping_add(12345); (x >> 16) as u8 }).collect()
+    }
+
+    fn round_trip(format: PatchFormat, source: &[u8], target: &[u8]) -> Patch {
+        let patch = Patch::create(format, source, target).unwrap();
+        let parsed = Patch::parse(&patch.to_bytes()).unwrap();
+        assert_eq!(parsed.apply(source).unwrap(), target);
+        parsed
+    }
+
+    #[test]
+    fn ips_rle() {
+        let source = vec![0; 0x400];
+        let mut target = source.clone();
+        target[0x10..0x50].fill(0xAA);
+        target[0x50..0x53].copy_from_slice(&[1, 2, 3]);
+        target[0x200] = 0x55;
+        let Patch::Ips(patch) = round_trip(PatchFormat::Ips, &source, &target) else { panic!() };
+        assert!(patch.records.iter().any(|c| matches!(c.data, IpsData::Rle { len: 0x40, byte: 0xAA })));
+        assert_eq!(patch.truncate, None);
+
+        // shrinking the image goes in the trailer
+        let Patch::Ips(patch) = round_trip(PatchFormat::Ips, &source, &target[..0x300]) else { panic!() };
+        assert_eq!(patch.truncate, Some(0x300));
+    }
+
+    #[test]
+    fn ips_eof_offset() {
+        let eof = IpsPatch::EOF_OFFSET;
+        let source = vec![0; eof + 0x100];
+        let mut target = source.clone();
+        target[eof] = 1;
+        let Patch::Ips(patch) = round_trip(PatchFormat::Ips, &source, &target) else { panic!() };
+        assert!(patch.records.iter().all(|c| c.offset != eof));
+
+        // a run starting there too
+        let mut target = source.clone();
+        target[eof..eof + 0x20].fill(0xEE);
+        let Patch::Ips(patch) = round_trip(PatchFormat::Ips, &source, &target) else { panic!() };
+        assert!(patch.records.iter().all(|c| c.offset != eof));
+        assert!(patch.records.iter().any(|c| matches!(c.data, IpsData::Rle { .. })));
+
+        assert_eq!(IpsPatch::create(&source, &vec![1; 0x1000001]).unwrap_err(), PatchError::TooLarge { offset: 0x1000000 });
+    }
+
+    #[test]
+    fn truncated() {
+        let source = vec![0; 0x100];
+        let mut target = source.clone();
+        target[0x20..0x28].copy_from_slice(b"modified");
+        let bytes = Patch::create(PatchFormat::Ips, &source, &target).unwrap().to_bytes();
+        for len in [5, 8, 10, bytes.len() - 3] {
+            assert_eq!(Patch::parse(&bytes[..len]).unwrap_err(), PatchError::Truncated, "cut at {}", len);
+        }
+        assert_eq!(Patch::parse(b"BPS1\x80").unwrap_err(), PatchError::Truncated);
+        assert_eq!(Patch::parse(b"PAT").unwrap_err(), PatchError::UnknownFormat);
+    }
+
+    #[test]
+    fn bps_copies() {
+        let source = noise(0x100, 1);
+        let actions = vec![
+            BpsAction::SourceCopy { len: 0x40, offset: 0x80 },
+            // back to before where the last copy ended
+            BpsAction::SourceCopy { len: 0x20, offset: 0x10 },
+            BpsAction::TargetRead { data: vec![1, 2, 3, 4] },
+            BpsAction::TargetCopy { len: 0x10, offset: 0x30 },
+            // and overlapping its own output
+            BpsAction::TargetCopy { len: 0x10, offset: 0x08 },
+            BpsAction::TargetCopy { len: 0x0C, offset: 0x70 },
+            BpsAction::SourceRead { len: 4 },
+        ];
+        let mut target = vec![];
+        target.extend_from_slice(&source[0x80..0xC0]);
+        target.extend_from_slice(&source[0x10..0x30]);
+        target.extend_from_slice(&[1, 2, 3, 4]);
+        for offset in [0x30..0x40, 0x08..0x18] {
+            target.extend_from_slice(&target.clone()[offset]);
+        }
+        for i in 0x70..0x7C { target.push(target[i]); }
+        let pos = target.len();
+        target.extend_from_slice(&source[pos..pos + 4]);
+
+        let patch = BpsPatch {
+            source_size: source.len(),
+            target_size: target.len(),
+            metadata: String::new(),
+            actions: actions.clone(),
+            source_crc: crc32(&source),
+            target_crc: crc32(&target),
+            patch_crc: 0,
+        };
+        let parsed = BpsPatch::parse(&patch.to_bytes()).unwrap();
+        assert_eq!(parsed.actions, actions);
+        assert_eq!(parsed.apply(&source).unwrap(), target);
+
+        // and what create comes up with for moved and repeated data
+        let mut target = source[0x80..].to_vec();
+        target.extend_from_slice(&source[..0x80]);
+        target.extend_from_slice(&source[0x40..0x60]);
+        target.extend_from_slice(&noise(0x10, 2).repeat(4));
+        let Patch::Bps(patch) = round_trip(PatchFormat::Bps, &source, &target) else { panic!() };
+        assert!(patch.actions.iter().any(|c| matches!(c, BpsAction::SourceCopy { offset: 0, .. })));
+        assert!(patch.actions.iter().any(|c| matches!(c, BpsAction::TargetCopy { .. })));
+    }
+
+    #[test]
+    fn bps_crc() {
+        let source = noise(0x100, 3);
+        let mut target = source.clone();
+        target[0x40..0x48].fill(0);
+        let patch = BpsPatch::create(&source, &target);
+        let mut bytes = patch.to_bytes();
+
+        let mut other = source.clone();
+        other[0] ^= 1;
+        let actual = crc32(&other);
+        assert_eq!(patch.apply(&other).unwrap_err(), PatchError::SourceCrc { expected: patch.source_crc, actual });
+        assert!(matches!(patch.apply(&source[1..]).unwrap_err(), PatchError::SourceSize { .. }));
+
+        let wrong = BpsPatch { target_crc: !patch.target_crc, ..patch.clone() };
+        assert!(matches!(wrong.apply(&source).unwrap_err(), PatchError::TargetCrc { .. }));
+
+        bytes[6] ^= 0xFF;
+        assert!(matches!(Patch::parse(&bytes).unwrap_err(), PatchError::PatchCrc { .. }));
+    }
+}
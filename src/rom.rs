@@ -1,46 +1,631 @@
+use serde_derive::{Serialize, Deserialize};
+use std::ops::Range;
+
 #[derive(Clone)]
 pub struct Rom {
     cart: Vec<u8>,
-    mapper: Mapper
+    mapper: Mapper,
+    pub sa1: Sa1Registers,
+    /// Size of the cartridge SRAM (BW-RAM on SA-1), usually from the header
+    pub sram_size: usize,
 }
 
-#[derive(Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mapper {
     LoRom,
     HiRom,
-    Sa1
+    Sa1,
+    ExLoRom,
+    ExHiRom,
+}
+
+/// The SA-1 Super MMC bank registers, CXB/DXB/EXB/FXB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MmcRegister {
+    Cxb,
+    Dxb,
+    Exb,
+    Fxb,
+}
+
+/// SA-1 registers that affect static analysis. These are written by the
+/// S-CPU at runtime, so anything other than the power-on defaults has to
+/// come from rules, or for the vectors, from stores analysis can follow.
+#[derive(Copy, Clone, Debug)]
+pub struct Sa1Registers {
+    /// $2220-$2223. Bits 0-2 select a 1MB block, bit 7 makes the LoROM
+    /// area follow the register instead of the fixed block.
+    pub mmc: [u8; 4],
+    /// SA-1 reset vector, $2203-$2204
+    pub crv: Option<u16>,
+    /// SA-1 NMI vector, $2205-$2206
+    pub cnv: Option<u16>,
+    /// SA-1 IRQ vector, $2207-$2208
+    pub civ: Option<u16>,
+}
+
+impl Default for Sa1Registers {
+    fn default() -> Self {
+        Self { mmc: [0, 1, 2, 3], crv: None, cnv: None, civ: None }
+    }
+}
+
+impl Sa1Registers {
+    pub fn set_mmc(&mut self, reg: MmcRegister, value: u8) {
+        self.mmc[reg as usize] = value & 0x87;
+    }
+    /// Which 1MB block of the ROM the `mmc` register maps to in the
+    /// LoROM-style area ($00-$3F/$80-$BF:$8000-$FFFF).
+    fn lorom_block(&self, reg: usize) -> u32 {
+        if self.mmc[reg] & 0x80 != 0 { (self.mmc[reg] & 7) as u32 } else { reg as u32 }
+    }
+    /// Which 1MB block the `mmc` register maps to in the HiROM-style area
+    /// ($C0-$FF).
+    fn hirom_block(&self, reg: usize) -> u32 {
+        (self.mmc[reg] & 7) as u32
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RomError {
+    /// The mapper doesn't decode the address to ROM
+    Unmapped(u32),
+    /// The address maps to ROM, but the read runs past the end of the image
+    OutOfBounds { addr: u32, offset: usize },
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RomError::Unmapped(addr) => write!(f, "${:06X} is not mapped to ROM", addr),
+            RomError::OutOfBounds { addr, offset } => write!(f, "${:06X} (offset ${:06X}) is past the end of the ROM", addr, offset),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// The checksum fields of the internal header next to the checksum of the
+/// image they're in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub stored: u16,
+    pub complement: u16,
+    pub computed: u16,
+}
+
+impl Checksum {
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.computed && self.complement == !self.computed
+    }
 }
 
 impl Rom {
     pub fn new(cart: Vec<u8>, mapper: Mapper) -> Self {
-        Self { cart, mapper }
+        Self { cart, mapper, sa1: Default::default(), sram_size: 0 }
+    }
+    pub fn mapper(&self) -> Mapper {
+        self.mapper
+    }
+    /// The headerless ROM image, including any edits.
+    pub fn cart(&self) -> &[u8] {
+        &self.cart
+    }
+    pub fn is_rom(&self, addr: u32) -> bool {
+        !self.cart.is_empty() && self.mapper.is_rom(addr)
+    }
+    pub fn memory_map(&self) -> MemoryMap<'_> {
+        MemoryMap { rom: self }
     }
     pub fn load(&self, addr: u32) -> u8 {
-        let off = Self::map_rom(addr);
+        let off = self.map_rom(addr);
         self.cart[off]
     }
     pub fn load_u16(&self, addr: u32) -> u16 {
-        let off = Self::map_rom(addr);
+        let off = self.map_rom(addr);
         u16::from_le_bytes([self.cart[off], self.cart[off+1]])
     }
     pub fn load_u24(&self, addr: u32) -> u32 {
-        let off = Self::map_rom(addr);
+        let off = self.map_rom(addr);
         u32::from_le_bytes([self.cart[off], self.cart[off+1], self.cart[off+2], 0])
     }
     pub fn load_u32(&self, addr: u32) -> u32 {
-        let off = Self::map_rom(addr);
+        let off = self.map_rom(addr);
         u32::from_le_bytes([self.cart[off], self.cart[off+1], self.cart[off+2], self.cart[off+3]])
     }
+    pub fn try_map_rom(&self, addr: u32) -> Result<usize, RomError> {
+        if !self.is_rom(addr) { return Err(RomError::Unmapped(addr)); }
+        Ok(self.map_rom(addr))
+    }
+    fn try_bytes<const N: usize>(&self, addr: u32) -> Result<[u8; N], RomError> {
+        let offset = self.try_map_rom(addr)?;
+        let bytes = self.cart.get(offset..offset + N).ok_or(RomError::OutOfBounds { addr, offset })?;
+        Ok(bytes.try_into().unwrap())
+    }
+    pub fn try_load(&self, addr: u32) -> Result<u8, RomError> {
+        self.try_bytes::<1>(addr).map(|[c]| c)
+    }
+    pub fn try_load_u16(&self, addr: u32) -> Result<u16, RomError> {
+        self.try_bytes(addr).map(u16::from_le_bytes)
+    }
+    pub fn try_load_u24(&self, addr: u32) -> Result<u32, RomError> {
+        self.try_bytes::<3>(addr).map(|[a, b, c]| u32::from_le_bytes([a, b, c, 0]))
+    }
+    pub fn try_load_u32(&self, addr: u32) -> Result<u32, RomError> {
+        self.try_bytes(addr).map(u32::from_le_bytes)
+    }
+    /// Computes the header checksum for the internal header at `header`
+    /// ($xxC0): the 16-bit sum of the image, counting the checksum fields as
+    /// $FFFF/$0000 and mirroring non-power-of-two images out to the next
+    /// power of two.
+    pub fn compute_checksum(&self, header: usize) -> u16 {
+        // complement, then checksum
+        let fields = header + 0x1C..header + 0x20;
+        let placeholder = [0xFF, 0xFF, 0x00, 0x00];
+        let sum = |range: Range<usize>| -> u32 {
+            let mut sum = self.cart[range.clone()].iter().map(|&c| c as u32).sum::<u32>();
+            for i in fields.clone().filter(|i| range.contains(i)) {
+                sum = sum - self.cart[i] as u32 + placeholder[i - fields.start];
+            }
+            sum
+        };
+        // sum of `range` repeated out to `size` bytes
+        fn mirrored(sum: &dyn Fn(Range<usize>) -> u32, range: Range<usize>, size: usize) -> u32 {
+            if range.is_empty() { return 0; }
+            let part = 1 << range.len().ilog2();
+            if part == range.len() {
+                sum(range).wrapping_mul((size / part) as u32)
+            } else {
+                let rest = mirrored(sum, range.start + part..range.end, size - part);
+                sum(range.start..range.start + part).wrapping_add(rest)
+            }
+        }
+        mirrored(&sum, 0..self.cart.len(), self.cart.len().next_power_of_two()) as u16
+    }
+    /// Compares the checksum stored in the header at `header` with the
+    /// actual one, or `None` if the header is out of bounds.
+    pub fn verify_checksum(&self, header: usize) -> Option<Checksum> {
+        let fields = self.cart.get(header + 0x1C..header + 0x20)?;
+        Some(Checksum {
+            complement: u16::from_le_bytes([fields[0], fields[1]]),
+            stored: u16::from_le_bytes([fields[2], fields[3]]),
+            computed: self.compute_checksum(header),
+        })
+    }
+    /// Rewrites the checksum and complement of the header at `header`.
+    pub fn fix_checksum(&mut self, header: usize) -> Option<Checksum> {
+        if header + 0x20 > self.cart.len() { return None; }
+        let computed = self.compute_checksum(header);
+        self.cart[header + 0x1C..header + 0x1E].copy_from_slice(&(!computed).to_le_bytes());
+        self.cart[header + 0x1E..header + 0x20].copy_from_slice(&computed.to_le_bytes());
+        self.verify_checksum(header)
+    }
+    pub fn store(&mut self, addr: u32, val: u8) -> Result<(), RomError> {
+        let offset = self.try_map_rom(addr)?;
+        let byte = self.cart.get_mut(offset).ok_or(RomError::OutOfBounds { addr, offset })?;
+        *byte = val;
+        Ok(())
+    }
+    pub fn try_slice(&self, addr: u32) -> Result<&[u8], RomError> {
+        let offset = self.try_map_rom(addr)?;
+        if offset >= self.cart.len() { return Err(RomError::OutOfBounds { addr, offset }); }
+        Ok(self.slice(addr))
+    }
+    /// Returns the bytes from `addr` up to the end of the contiguous ROM window
+    /// it lives in (32K for LoROM banks, 64K for HiROM banks).
     pub fn slice(&self, addr: u32) -> &[u8] {
-        let off = Self::map_rom(addr);
-        let end = (off & 0xFF8000) + 0x8000;
-        &self.cart[off..end]
-    }
-    pub fn map_rom(addr: u32) -> usize {
-        // TODO: mapper support
-        let mut bank = (addr >> 16) & 0x3F;
-        if bank & 0x30 == 0x30 { bank &= !0x10; }
-        let addr = addr & 0x7FFF;
-        (bank << 15 | addr) as _
+        let off = self.map_rom(addr);
+        let window = self.mapper.window_size(addr);
+        let end = (off & !(window - 1)) + window;
+        &self.cart[off..end.min(self.cart.len())]
+    }
+    pub fn map_rom(&self, addr: u32) -> usize {
+        mirror(self.map_rom_unmirrored(addr), self.cart.len())
+    }
+    /// Like `map_rom`, but without folding the result into the size of the
+    /// ROM. Useful for telling apart an address from its mirrors.
+    pub fn map_rom_unmirrored(&self, addr: u32) -> usize {
+        let bank = (addr >> 16) & 0xFF;
+        let addr = addr & 0xFFFF;
+        let off = match self.mapper {
+            Mapper::LoRom => {
+                (bank & 0x7F) << 15 | (addr & 0x7FFF)
+            }
+            Mapper::Sa1 if bank >= 0xC0 => {
+                let block = self.sa1.hirom_block((bank as usize >> 4) & 3);
+                block << 20 | (bank & 0x0F) << 16 | addr
+            }
+            Mapper::Sa1 => {
+                // $00-$1F, $20-$3F, $80-$9F and $A0-$BF each get a register
+                let block = self.sa1.lorom_block(((bank >> 5) & 1 | (bank >> 6) & 2) as usize);
+                block << 20 | (bank & 0x1F) << 15 | (addr & 0x7FFF)
+            }
+            Mapper::HiRom => {
+                // $00-$3F/$80-$BF:$8000-$FFFF is the upper half of the 64K bank
+                // mirrored at $40-$7D/$C0-$FF, so both map the same way.
+                (bank & 0x3F) << 16 | addr
+            }
+            Mapper::ExLoRom => {
+                // $80-$FF is the first 4MB, $00-$7D the second
+                let high = if bank & 0x80 == 0 { 0x400000 } else { 0 };
+                high | (bank & 0x7F) << 15 | (addr & 0x7FFF)
+            }
+            Mapper::ExHiRom => {
+                // $C0-$FF is the first 4MB, $40-$7D the second
+                let high = if bank & 0x80 == 0 { 0x400000 } else { 0 };
+                high | (bank & 0x3F) << 16 | addr
+            }
+        };
+        off as usize
+    }
+    /// The canonical SNES address of a file offset, or `None` if the
+    /// offset can't be reached with the current mapping.
+    ///
+    /// LoROM-style offsets use $00-$7D:8000 (falling back to the $80 mirror
+    /// where the bank would be WRAM), HiROM-style offsets use the full 64K
+    /// banks at $C0-$FF, or $40-$7D for the second half of ExHiROM.
+    pub fn unmap_rom(&self, off: usize) -> Option<u32> {
+        if off >= self.cart.len() { return None; }
+        let off = off as u32;
+        let lorom = |bank: u32| 0x8000 | bank << 16 | (off & 0x7FFF);
+        Some(match self.mapper {
+            Mapper::LoRom if off < 0x400000 => {
+                let bank = off >> 15;
+                lorom(if bank < 0x7E { bank } else { bank | 0x80 })
+            }
+            Mapper::HiRom if off < 0x400000 => 0xC00000 | off,
+            Mapper::ExLoRom if off < 0x400000 => lorom(0x80 | off >> 15),
+            Mapper::ExLoRom if off < 0x7F0000 => lorom((off - 0x400000) >> 15),
+            Mapper::ExHiRom if off < 0x400000 => 0xC00000 | off,
+            Mapper::ExHiRom if off < 0x7E0000 => 0x400000 | (off - 0x400000),
+            Mapper::ExHiRom if off & 0x8000 != 0 => off - 0x400000,
+            Mapper::Sa1 => {
+                let block = off >> 20;
+                if let Some(reg) = (0..4).find(|&r| self.sa1.hirom_block(r) == block) {
+                    0xC00000 | (reg as u32) << 20 | (off & 0xFFFFF)
+                } else if let Some(reg) = (0..4).find(|&r| self.sa1.lorom_block(r) == block) {
+                    let bank = [0x00, 0x20, 0x80, 0xA0][reg] | (off >> 15) & 0x1F;
+                    lorom(bank)
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        })
+    }
+    /// Every SNES address that maps to the file offset, without counting
+    /// mirrors caused by the ROM being smaller than the address space.
+    pub fn mirrors(&self, off: usize) -> Vec<u32> {
+        let mut out = vec![];
+        for bank in 0..0x100u32 {
+            for addr in [off as u32 & 0xFFFF, off as u32 & 0x7FFF, 0x8000 | (off as u32 & 0x7FFF)] {
+                let addr = bank << 16 | addr;
+                if !out.contains(&addr) && self.is_rom(addr) && self.map_rom_unmirrored(addr) == off {
+                    out.push(addr);
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Mapper {
+    /// Whether `addr` is decoded to cartridge ROM by this mapper.
+    pub fn is_rom(self, addr: u32) -> bool {
+        let bank = (addr >> 16) & 0xFF;
+        if bank & 0xFE == 0x7E { return false; }
+        let upper = addr & 0x8000 != 0;
+        match self {
+            Mapper::LoRom | Mapper::ExLoRom => upper,
+            Mapper::HiRom | Mapper::ExHiRom => upper || bank & 0x40 != 0,
+            Mapper::Sa1 => match bank {
+                0x00..=0x3F | 0x80..=0xBF => upper,
+                0xC0..=0xFF => true,
+                // BW-RAM
+                _ => false,
+            },
+        }
+    }
+    /// Size of the contiguous ROM window that `addr` is in.
+    pub fn window_size(self, addr: u32) -> usize {
+        match self {
+            Mapper::LoRom | Mapper::ExLoRom => 0x8000,
+            Mapper::HiRom | Mapper::ExHiRom => if addr & 0x400000 != 0 { 0x10000 } else { 0x8000 },
+            Mapper::Sa1 => if addr & 0xC00000 == 0xC00000 { 0x10000 } else { 0x8000 },
+        }
+    }
+}
+
+/// What a 24-bit address on the S-CPU bus is connected to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Area {
+    Rom { offset: usize },
+    /// Work RAM, as its canonical $7E0000-$7FFFFF address
+    Wram(u32),
+    Sram { offset: usize },
+    /// B-bus registers, $2100-$21FF
+    PpuRegister(u16),
+    /// $4016-$4017 and $4200-$421F
+    CpuRegister(u16),
+    /// $4300-$437F
+    DmaRegister(u16),
+    /// Registers and memory of an on-cartridge chip, like the SA-1
+    Coprocessor(u16),
+    /// Other addresses in $2000-$7FFF that the cartridge may decode
+    Expansion(u16),
+    OpenBus,
+}
+
+/// Classifies addresses on the S-CPU bus for a particular cartridge.
+pub struct MemoryMap<'a> {
+    rom: &'a Rom,
+}
+
+impl MemoryMap<'_> {
+    pub fn classify(&self, addr: u32) -> Area {
+        let addr = addr & 0xFFFFFF;
+        let bank = addr >> 16;
+        let lo = (addr & 0xFFFF) as u16;
+        if bank & 0xFE == 0x7E {
+            return Area::Wram(addr);
+        }
+        let system = bank & 0x40 == 0;
+        if system && lo < 0x2000 {
+            return Area::Wram(0x7E0000 | lo as u32);
+        }
+        if system && lo < 0x8000 {
+            let sa1 = self.rom.mapper == Mapper::Sa1;
+            return match lo {
+                0x2100..=0x21FF => Area::PpuRegister(lo),
+                0x4016..=0x4017 | 0x4200..=0x421F => Area::CpuRegister(lo),
+                0x4300..=0x437F => Area::DmaRegister(lo),
+                // SA-1 registers and I-RAM
+                0x2200..=0x23FF | 0x3000..=0x37FF if sa1 => Area::Coprocessor(lo),
+                0x6000..=0x7FFF => match self.sram_offset(addr) {
+                    Some(offset) => Area::Sram { offset },
+                    None => Area::Expansion(lo),
+                },
+                0x2000..=0x20FF | 0x4000..=0x41FF | 0x4220..=0x42FF | 0x4380..=0x5FFF => Area::OpenBus,
+                _ => Area::Expansion(lo),
+            };
+        }
+        if self.rom.is_rom(addr) {
+            return Area::Rom { offset: self.rom.map_rom(addr) };
+        }
+        match self.sram_offset(addr) {
+            Some(offset) => Area::Sram { offset },
+            None => Area::OpenBus,
+        }
+    }
+    /// File offset into SRAM, if `addr` is decoded to it.
+    fn sram_offset(&self, addr: u32) -> Option<usize> {
+        let size = self.rom.sram_size;
+        if size == 0 { return None; }
+        let bank = (addr >> 16) as usize;
+        let lo = addr as usize & 0xFFFF;
+        let off = match self.rom.mapper {
+            Mapper::LoRom | Mapper::ExLoRom => match bank & 0x7F {
+                0x70..=0x7D if lo < 0x8000 => (bank & 0xF) << 15 | lo,
+                _ => return None,
+            },
+            Mapper::HiRom | Mapper::ExHiRom => match bank & 0x7F {
+                0x20..=0x3F if (0x6000..0x8000).contains(&lo) => (bank & 0x1F) << 13 | (lo - 0x6000),
+                _ => return None,
+            },
+            Mapper::Sa1 => match bank {
+                0x40..=0x4F => (bank & 0xF) << 16 | lo,
+                // the BW-RAM window, assuming the default block
+                0x00..=0x3F | 0x80..=0xBF if (0x6000..0x8000).contains(&lo) => lo - 0x6000,
+                _ => return None,
+            },
+        };
+        Some(mirror(off, size))
+    }
+    /// The address that labels and cross references for `addr` are kept
+    /// under: WRAM at $7E/$7F, I/O in bank $00, SRAM at its first mirror.
+    /// ROM addresses are left alone, since code labels use the bank the
+    /// code actually runs in, so lookups by a ROM address have to check
+    /// its mirrors as well.
+    pub fn canonical(&self, addr: u32) -> u32 {
+        let addr = addr & 0xFFFFFF;
+        match self.classify(addr) {
+            Area::Wram(c) => c,
+            Area::PpuRegister(c) | Area::CpuRegister(c) | Area::DmaRegister(c)
+                | Area::Coprocessor(c) | Area::Expansion(c) => c as u32,
+            Area::Sram { offset } => match self.rom.mapper {
+                Mapper::LoRom | Mapper::ExLoRom => 0x700000 + ((offset as u32 >> 15) << 16 | offset as u32 & 0x7FFF),
+                Mapper::HiRom | Mapper::ExHiRom => 0x206000 + ((offset as u32 >> 13) << 16 | offset as u32 & 0x1FFF),
+                Mapper::Sa1 => 0x400000 + offset as u32,
+            },
+            Area::Rom { .. } | Area::OpenBus => addr,
+        }
+    }
+}
+
+/// Maps a linear offset into a ROM of `size` bytes the way the cartridge
+/// address lines do: power-of-two ROMs wrap, and the top part of a
+/// non-power-of-two ROM is mirrored to fill out the next power of two.
+pub fn mirror(mut addr: usize, mut size: usize) -> usize {
+    if size == 0 { return 0; }
+    let mut base = 0;
+    let mut mask = 1 << 23;
+    while addr >= size {
+        while addr & mask == 0 { mask >>= 1; }
+        addr -= mask;
+        if size > mask {
+            size -= mask;
+            base += mask;
+        }
+        mask >>= 1;
+    }
+    base + addr
+}
+
+/// A ROM image turned into the linear layout `Rom` expects, along with
+/// what had to be done to get there.
+#[derive(Clone, Debug)]
+pub struct RomImage {
+    pub cart: Vec<u8>,
+    /// Copier header of the first part, if it had one
+    pub copier_header: Vec<u8>,
+    pub steps: Vec<Normalization>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// A copier header of `size` bytes was removed from part `part`
+    StrippedHeader { part: usize, size: usize },
+    /// The image was split across several files
+    Joined { parts: usize },
+    /// The 32K halves of each 64K bank were stored separately, as some
+    /// copiers (Game Doctor, SWC) do for HiROM games
+    Deinterleaved,
+}
+
+impl std::fmt::Display for Normalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Normalization::StrippedHeader { part, size } => write!(f, "removed a {} byte copier header from part {}", size, part + 1),
+            Normalization::Joined { parts } => write!(f, "joined {} parts", parts),
+            Normalization::Deinterleaved => write!(f, "de-interleaved HiROM banks"),
+        }
+    }
+}
+
+impl RomImage {
+    /// Builds a linear image from the parts of a dump, in order. Most dumps
+    /// are a single part.
+    pub fn normalize(parts: Vec<Vec<u8>>) -> Self {
+        let mut cart = vec![];
+        let mut copier_header = vec![];
+        let mut steps = vec![];
+        let count = parts.len();
+        for (part, mut data) in parts.into_iter().enumerate() {
+            // dumps are a multiple of 1K, anything past that is a header
+            let size = data.len() % 0x400;
+            if size != 0 {
+                let header: Vec<u8> = data.drain(..size).collect();
+                if part == 0 { copier_header = header; }
+                steps.push(Normalization::StrippedHeader { part, size });
+            }
+            cart.extend(data);
+        }
+        if count > 1 {
+            steps.push(Normalization::Joined { parts: count });
+        }
+        if is_interleaved(&cart) {
+            cart = deinterleave(&cart);
+            steps.push(Normalization::Deinterleaved);
+        }
+        Self { cart, copier_header, steps }
+    }
+}
+
+/// Interleaved HiROM images store the upper 32K of every bank first, which
+/// puts a HiROM header where a LoROM one would be.
+fn is_interleaved(cart: &[u8]) -> bool {
+    if cart.is_empty() || cart.len() % 0x10000 != 0 { return false; }
+    let Some(linear) = crate::header::detect(cart) else { return false };
+    if linear.offset != 0x7FC0 || linear.mapper != Mapper::HiRom { return false; }
+    crate::header::detect(&deinterleave(cart))
+        .map_or(false, |c| c.offset == 0xFFC0 && c.score > linear.score)
+}
+
+fn deinterleave(cart: &[u8]) -> Vec<u8> {
+    let banks = cart.len() / 0x10000;
+    let half = |i: usize| &cart[i * 0x8000..(i + 1) * 0x8000];
+    (0..banks).flat_map(|i| [half(banks + i), half(i)]).flatten().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offsets at the start and end of every 16K, which covers the edges of
+    /// both LoROM and HiROM banks.
+    fn offsets(len: usize) -> impl Iterator<Item=usize> {
+        (0..len).step_by(0x4000).flat_map(|c| [c, c + 0x3FFF])
+    }
+
+    /// Checks that every offset the mapping can reach comes back to itself,
+    /// and returns how many could.
+    fn round_trip(rom: &Rom) -> usize {
+        let mut reached = 0;
+        for off in offsets(rom.cart().len()) {
+            let Some(addr) = rom.unmap_rom(off) else { continue };
+            assert!(rom.is_rom(addr), "{:?} {:X} -> {:06X}", rom.mapper(), off, addr);
+            assert_eq!(rom.map_rom(addr), off, "{:?} {:X} -> {:06X}", rom.mapper(), off, addr);
+            reached += 1;
+        }
+        reached
+    }
+
+    #[test]
+    fn unmap_round_trip() {
+        for (mapper, len) in [(Mapper::LoRom, 0x400000), (Mapper::HiRom, 0x400000), (Mapper::ExLoRom, 0x800000), (Mapper::ExHiRom, 0x800000), (Mapper::Sa1, 0x800000)] {
+            let rom = Rom::new(vec![0; len], mapper);
+            let reached = round_trip(&rom);
+            match mapper {
+                // $7E/$7F are WRAM, which leaves the last 64K unreachable
+                // with ExLoROM, and only the $3E/$3F mirrors with ExHiROM
+                Mapper::ExLoRom => assert_eq!(reached, offsets(0x7F0000).count()),
+                Mapper::ExHiRom => assert_eq!(reached, offsets(0x7E0000).count() + offsets(0x10000).count()),
+                // only four of the eight blocks are mapped at power-on
+                Mapper::Sa1 => assert_eq!(reached, offsets(0x400000).count()),
+                _ => assert_eq!(reached, offsets(len).count()),
+            }
+        }
+    }
+
+    #[test]
+    fn ex_banks() {
+        let rom = Rom::new(vec![0; 0x800000], Mapper::ExLoRom);
+        assert_eq!(rom.map_rom(0x3E8000), 0x5F0000);
+        assert_eq!(rom.map_rom(0x3FFFFF), 0x5FFFFF);
+        assert_eq!(rom.map_rom(0xFE8000), 0x3F0000);
+        assert_eq!(rom.map_rom(0xFFFFFF), 0x3FFFFF);
+        assert_eq!(rom.unmap_rom(0x5F8000), Some(0x3F8000));
+        assert!(!rom.is_rom(0x7E8000) && !rom.is_rom(0x7F8000));
+        assert_eq!(rom.unmap_rom(0x7F0000), None);
+
+        let rom = Rom::new(vec![0; 0x800000], Mapper::ExHiRom);
+        assert_eq!(rom.map_rom(0x3E8000), 0x7E8000);
+        assert_eq!(rom.map_rom(0x3FFFFF), 0x7FFFFF);
+        assert_eq!(rom.map_rom(0xFE0000), 0x3E0000);
+        assert_eq!(rom.map_rom(0xFFFFFF), 0x3FFFFF);
+        assert_eq!(rom.unmap_rom(0x7F8000), Some(0x3F8000));
+        assert!(!rom.is_rom(0x7E8000) && !rom.is_rom(0x7F0000));
+        assert_eq!(rom.unmap_rom(0x7F0000), None);
+    }
+
+    #[test]
+    fn sa1_blocks() {
+        let mut rom = Rom::new(vec![0; 0x800000], Mapper::Sa1);
+        rom.sa1.set_mmc(MmcRegister::Fxb, 5);
+        rom.sa1.set_mmc(MmcRegister::Cxb, 0x86);
+        assert_eq!(rom.map_rom(0xF00000), 0x500000);
+        assert_eq!(rom.map_rom(0x008000), 0x600000);
+        // without bit 7 the LoROM area stays on the default block
+        rom.sa1.set_mmc(MmcRegister::Dxb, 0x07);
+        assert_eq!(rom.map_rom(0x208000), 0x100000);
+        round_trip(&rom);
+        assert_eq!(rom.unmap_rom(0x500000), Some(0xF00000));
+        assert_eq!(rom.unmap_rom(0x400000), None);
+    }
+
+    #[test]
+    fn non_power_of_two() {
+        // the top 1MB of a 3MB ROM fills out the 4MB space
+        assert_eq!(mirror(0x300000, 0x300000), 0x200000);
+        assert_eq!(mirror(0x3FFFFF, 0x300000), 0x2FFFFF);
+        assert_eq!(mirror(0x1C0000, 0x180000), 0x140000);
+        assert_eq!(mirror(0x400000, 0x300000), 0);
+        for off in offsets(0x300000) {
+            assert_eq!(mirror(off, 0x300000), off);
+        }
+        let rom = Rom::new(vec![0; 0x300000], Mapper::LoRom);
+        assert_eq!(round_trip(&rom), offsets(0x300000).count());
+        assert_eq!(rom.map_rom(0x608000), 0x200000);
+        assert_eq!(rom.map_rom(0xE08000), 0x200000);
     }
 }